@@ -0,0 +1,28 @@
+#!/bin/sh
+# Benchmarks an earlier revision with the current benches/server.rs, then compares the current
+# tree against it.
+#
+# Usage: benches/compare_baseline.sh [revision]
+#
+# The revision defaults to the tree-walking interpreter from before timelines were compiled to
+# bytecode, which has no compile step, so only `story/run` compares like with like. The revision
+# needs the `Server` API the benchmark uses.
+set -eu
+
+root=$(git rev-parse --show-toplevel)
+revision=${1:-$(git -C "$root" log -1 --format=%H --diff-filter=A -- src/bytecode.rs)~1}
+worktree=$(mktemp -d)
+trap 'git -C "$root" worktree remove --force "$worktree"' EXIT
+
+git -C "$root" worktree add --detach "$worktree" "$revision"
+mkdir -p "$worktree/benches"
+cp "$root/benches/server.rs" "$worktree/benches/server.rs"
+if ! grep -q '^\[\[bench\]\]' "$worktree/Cargo.toml"; then
+    printf '\n[dev-dependencies]\ncriterion = "0.5"\n\n[[bench]]\nname = "server"\nharness = false\n' \
+        >>"$worktree/Cargo.toml"
+fi
+
+# Both runs keep their results in the same place so they can be compared.
+export CRITERION_HOME="$root/target/criterion"
+(cd "$worktree" && CARGO_TARGET_DIR="$root/target/baseline" cargo bench --bench server -- --save-baseline baseline)
+(cd "$root" && cargo bench --bench server -- --baseline baseline)
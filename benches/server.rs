@@ -0,0 +1,84 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use evalexpr::{ContextWithMutableVariables, HashMapContext, Value};
+use nobela::{
+    parser::Parser,
+    server::{Event, Server, Timelines},
+};
+
+/// A story of `length` scenes, each with nested choices and conditions and a call.
+fn story(length: usize) -> Timelines {
+    let mut script = String::new();
+    for i in 0..length {
+        script.push_str(&format!(
+            r#""Scene {i}: the [b]road[/b] splits, {{gold}} gold left."
+-- "Go left"
+	if gold > 5:
+		"You are rich."
+		"Very rich."
+	"Left."
+-- "Go right" if gold > 100
+	"Right."
+-- "Wait"
+	"Waiting."
+if gold < 5:
+	"You are poor."
+call "aside"
+"#
+        ));
+    }
+    script.push_str(r#""The end.""#);
+
+    let parser = Parser::new(vec![]);
+    Timelines::from([
+        ("start".to_owned(), parser.parse(&script).unwrap()),
+        (
+            "aside".to_owned(),
+            parser.parse(r#""Meanwhile, elsewhere...""#).unwrap(),
+        ),
+    ])
+}
+
+fn context() -> HashMapContext {
+    let mut context = HashMapContext::new();
+    context
+        .set_value("gold".to_owned(), Value::Int(10))
+        .unwrap();
+    context
+}
+
+fn run_server(mut server: Server) -> usize {
+    server.start("start", 0);
+    let mut count = 0;
+    while let Some(event) = server.next() {
+        if matches!(&event, Event::Dialogue { choices, .. } if !choices.is_empty()) {
+            server.choose(0);
+        }
+        count += 1;
+    }
+    count
+}
+
+/// `benches/compare_baseline.sh` runs this against the interpreter from before the bytecode VM.
+fn bench_story(c: &mut Criterion) {
+    let timelines = story(200);
+
+    let mut group = c.benchmark_group("story");
+    group.bench_function("run", |b| {
+        b.iter_batched(
+            || Server::new(timelines.to_owned(), context()),
+            run_server,
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("compile", |b| {
+        b.iter_batched(
+            || timelines.to_owned(),
+            |timelines| Server::new(timelines, context()),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_story);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+//! Precompiled stories, so games can ship parsed timelines instead of `.nobela` sources.
+//!
+//! A bundle is a JSON file holding the timelines, the characters and the format version it was
+//! written with.
+
+use std::{fmt, fs};
+
+use evalexpr::HashMapContext;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    character::{CharacterRegistry, Characters},
+    server::{Server, Timelines},
+};
+
+/// The bundle format version. Bundles written with another version must be built again.
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BundleError {
+    Io { path: String, message: String },
+    Format { message: String },
+    Version { found: u32 },
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Io { path, message } => write!(f, "{path}: {message}"),
+            BundleError::Format { message } => write!(f, "Invalid bundle: {message}"),
+            BundleError::Version { found } => write!(
+                f,
+                "Bundle version {found} isn't supported, expected {BUNDLE_VERSION}. Build it again."
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bundle {
+    pub version: u32,
+    pub timelines: Timelines,
+    pub characters: Characters,
+}
+
+impl Bundle {
+    pub fn new(timelines: Timelines, characters: Characters) -> Self {
+        Bundle {
+            version: BUNDLE_VERSION,
+            timelines,
+            characters,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Only maps with string keys are serialized, which can't fail.
+        serde_json::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BundleError> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+        }
+
+        // Checks the version first, so an old bundle isn't reported as malformed.
+        let header: Header = serde_json::from_slice(bytes).map_err(|e| BundleError::Format {
+            message: e.to_string(),
+        })?;
+        if header.version != BUNDLE_VERSION {
+            return Err(BundleError::Version {
+                found: header.version,
+            });
+        }
+
+        serde_json::from_slice(bytes).map_err(|e| BundleError::Format {
+            message: e.to_string(),
+        })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), BundleError> {
+        fs::write(path, self.to_bytes()).map_err(|e| BundleError::Io {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, BundleError> {
+        let bytes = fs::read(path).map_err(|e| BundleError::Io {
+            path: path.to_owned(),
+            message: e.to_string(),
+        })?;
+        Bundle::from_bytes(&bytes)
+    }
+
+    /// Creates a server running the bundled story with its characters.
+    pub fn into_server(self, context: HashMapContext) -> Server {
+        let mut server = Server::new(self.timelines, context);
+        server.set_character_registry(CharacterRegistry::new(self.characters));
+        server
+    }
+}
@@ -0,0 +1,593 @@
+//! Compiles timelines into the instructions [`crate::server::Server`] runs.
+//!
+//! Each statement compiles to exactly one instruction, so a position in a timeline is also a
+//! position in its code and positions in saves stay valid. The block structure is resolved into
+//! jump targets at compile time, and timeline names are interned so calls don't look names up.
+
+use std::collections::{HashMap, HashSet};
+
+use evalexpr::{
+    build_operator_tree, eval, eval_boolean_with_context, eval_with_context, Context,
+    EvalexprResult, Node, Value,
+};
+
+use crate::{
+    parser::Stmt,
+    server::{Timeline, Timelines},
+    template::Alternation,
+};
+
+pub type TimelineId = usize;
+
+/// An expression, parsed once at compile time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub source: String,
+    /// `None` if the expression doesn't parse, so the error is reported when it's evaluated.
+    tree: Option<Node>,
+}
+
+impl Expr {
+    pub fn new(source: String) -> Self {
+        let tree = build_operator_tree(&source).ok();
+        Expr { source, tree }
+    }
+
+    pub fn eval<C: Context>(&self, context: &C) -> EvalexprResult<Value> {
+        match &self.tree {
+            Some(tree) => tree.eval_with_context(context),
+            None => eval_with_context(&self.source, context),
+        }
+    }
+
+    pub fn eval_boolean<C: Context>(&self, context: &C) -> EvalexprResult<bool> {
+        match &self.tree {
+            Some(tree) => tree.eval_boolean_with_context(context),
+            None => eval_boolean_with_context(&self.source, context),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Emits a dialogue line offering the choices at the given positions.
+    Line {
+        character_id: Option<String>,
+        speaker: Option<String>,
+        text: String,
+        expression: Option<String>,
+        line_id: Option<String>,
+        voice: Option<String>,
+        choices: Vec<usize>,
+        /// Where its choices end, which is where it continues when none of them can be chosen.
+        end: usize,
+    },
+    /// A choice of the line before it. Only read by [`Op::Line`], skipped when reached.
+    Choice {
+        text: String,
+        condition: Option<Expr>,
+        line_id: Option<String>,
+        once: bool,
+        fallback: bool,
+    },
+    Jump {
+        target: usize,
+    },
+    JumpIfFalse {
+        condition: Expr,
+        target: usize,
+    },
+    Call {
+        timeline: TimelineId,
+        jump: bool,
+        arguments: Vec<(String, Expr)>,
+    },
+    Set {
+        variable_name: String,
+        expression: Expr,
+    },
+    Show {
+        character_id: String,
+        expression: Option<String>,
+        position: Option<String>,
+    },
+    Hide {
+        character_id: String,
+    },
+    Move {
+        character_id: String,
+        position: String,
+    },
+    Return,
+    End,
+    /// Counts a visit to the label, named `timeline#label`.
+    Label {
+        name: String,
+    },
+    /// Jumps to one of the alternatives at `targets`, or to `end` if none is picked.
+    Alternatives {
+        alternation: Alternation,
+        targets: Vec<usize>,
+        end: usize,
+        /// The line ID of the first line of the block, which identifies it across saves.
+        line_id: Option<String>,
+    },
+    /// Evaluates `expression` once and jumps to the first of `cases` whose value equals it, or
+    /// to `default`.
+    Match {
+        expression: Expr,
+        cases: Vec<(Value, usize)>,
+        default: usize,
+    },
+    /// Takes the place of the end of a block.
+    Nop,
+}
+
+/// Compiled timelines.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    names: Vec<String>,
+    ids: HashMap<String, TimelineId>,
+    /// The code of each timeline, or `None` for names that are called but not defined.
+    code: Vec<Option<Vec<Op>>>,
+    /// The labels of all timelines, as `timeline#label`.
+    labels: HashSet<String>,
+}
+
+impl Program {
+    pub fn compile(timelines: Timelines) -> Self {
+        let mut program = Program::default();
+        for (name, timeline) in timelines {
+            let id = program.intern(&name);
+            let code = program.compile_timeline(&name, timeline);
+            program.code[id] = Some(code);
+        }
+        program
+    }
+
+    fn intern(&mut self, name: &str) -> TimelineId {
+        match self.ids.get(name) {
+            Some(id) => *id,
+            None => {
+                let id = self.names.len();
+                self.names.push(name.to_owned());
+                self.ids.insert(name.to_owned(), id);
+                self.code.push(None);
+                id
+            }
+        }
+    }
+
+    fn compile_timeline(&mut self, name: &str, timeline: Timeline) -> Vec<Op> {
+        let loop_targets = loop_targets(&timeline);
+        let mut alternative_targets = alternative_targets(&timeline);
+        let mut match_targets = match_targets(&timeline);
+        let mut alternative_line_ids: HashMap<usize, String> = timeline
+            .iter()
+            .enumerate()
+            .filter(|(_, stmt)| matches!(stmt, Stmt::Alternatives { .. }))
+            .filter_map(|(index, _)| {
+                let end = *alternative_targets[&index].last().unwrap();
+                let line_id = timeline[index..end].iter().find_map(|stmt| match stmt {
+                    Stmt::Dialogue { line_id, .. } | Stmt::Choice { line_id, .. } => {
+                        line_id.to_owned()
+                    }
+                    _ => None,
+                });
+                line_id.map(|line_id| (index, line_id))
+            })
+            .collect();
+        let case_values: HashMap<usize, Option<String>> = timeline
+            .iter()
+            .enumerate()
+            .filter_map(|(index, stmt)| match stmt {
+                Stmt::Case { value } => Some((index, value.to_owned())),
+                _ => None,
+            })
+            .collect();
+        let targets: Vec<Vec<usize>> = (0..timeline.len())
+            .map(|index| match &timeline[index] {
+                Stmt::Dialogue { .. } => {
+                    let (mut choices, end) = choices(&timeline, index);
+                    choices.push(end);
+                    choices
+                }
+                Stmt::EndChoice => vec![end_of_choices(&timeline, index)],
+                Stmt::If { .. } => vec![end_of_if(&timeline, index)],
+                Stmt::Loop { .. } | Stmt::EndLoop | Stmt::Break => vec![loop_targets[&index]],
+                Stmt::Alternatives { .. } | Stmt::EndAlternative => {
+                    alternative_targets.remove(&index).unwrap()
+                }
+                Stmt::Match { .. } | Stmt::EndCase => match_targets.remove(&index).unwrap(),
+                _ => vec![],
+            })
+            .collect();
+
+        timeline
+            .into_iter()
+            .zip(targets)
+            .enumerate()
+            .map(|(index, (stmt, mut targets))| match stmt {
+                Stmt::Dialogue {
+                    character_id,
+                    speaker,
+                    text,
+                    expression,
+                    line_id,
+                    voice,
+                } => Op::Line {
+                    character_id,
+                    speaker,
+                    text,
+                    expression,
+                    line_id,
+                    voice,
+                    end: targets.pop().unwrap(),
+                    choices: targets,
+                },
+                Stmt::Choice {
+                    text,
+                    condition,
+                    line_id,
+                    once,
+                    fallback,
+                } => Op::Choice {
+                    text,
+                    condition: condition.map(Expr::new),
+                    line_id,
+                    once,
+                    fallback,
+                },
+                Stmt::EndChoice => Op::Jump {
+                    target: targets.remove(0),
+                },
+                Stmt::If { condition } => Op::JumpIfFalse {
+                    condition: Expr::new(condition),
+                    target: targets.remove(0),
+                },
+                Stmt::Call {
+                    jump,
+                    timeline_name,
+                    arguments,
+                } => Op::Call {
+                    timeline: self.intern(&timeline_name),
+                    jump,
+                    arguments: arguments
+                        .into_iter()
+                        .map(|(name, expression)| (name, Expr::new(expression)))
+                        .collect(),
+                },
+                Stmt::Set {
+                    variable_name,
+                    expression,
+                } => Op::Set {
+                    variable_name,
+                    expression: Expr::new(expression),
+                },
+                Stmt::Show {
+                    character_id,
+                    expression,
+                    position,
+                } => Op::Show {
+                    character_id,
+                    expression,
+                    position,
+                },
+                Stmt::Hide { character_id } => Op::Hide { character_id },
+                Stmt::Move {
+                    character_id,
+                    position,
+                } => Op::Move {
+                    character_id,
+                    position,
+                },
+                Stmt::Loop {
+                    condition: Some(condition),
+                } => Op::JumpIfFalse {
+                    condition: Expr::new(condition),
+                    target: targets.remove(0),
+                },
+                Stmt::Loop { condition: None } => Op::Nop,
+                Stmt::EndLoop | Stmt::Break => Op::Jump {
+                    target: targets.remove(0),
+                },
+                Stmt::Return => Op::Return,
+                Stmt::End => Op::End,
+                Stmt::Label { name: label } => {
+                    let label = label_key(name, &label);
+                    self.labels.insert(label.to_owned());
+                    Op::Label { name: label }
+                }
+                Stmt::Alternatives { alternation } => Op::Alternatives {
+                    alternation,
+                    end: targets.pop().unwrap(),
+                    targets,
+                    line_id: alternative_line_ids.remove(&index),
+                },
+                Stmt::EndAlternative | Stmt::EndCase => Op::Jump {
+                    target: targets.remove(0),
+                },
+                Stmt::Match { expression } => {
+                    let end = targets.pop().unwrap();
+                    let mut cases = Vec::new();
+                    let mut default = end;
+                    for target in targets {
+                        match &case_values[&target] {
+                            // The parser only allows literals.
+                            Some(value) => cases.push((
+                                eval(value)
+                                    .unwrap_or_else(|e| panic!("Invalid case '{value}': {e}")),
+                                target,
+                            )),
+                            None => default = target,
+                        }
+                    }
+                    Op::Match {
+                        expression: Expr::new(expression),
+                        cases,
+                        default,
+                    }
+                }
+                Stmt::EndDialogue
+                | Stmt::EndIf
+                | Stmt::EndAlternatives
+                | Stmt::Case { .. }
+                | Stmt::EndMatch => Op::Nop,
+            })
+            .collect()
+    }
+
+    /// The ID of a defined timeline.
+    pub fn id(&self, timeline_name: &str) -> Option<TimelineId> {
+        self.ids
+            .get(timeline_name)
+            .copied()
+            .filter(|id| self.code[*id].is_some())
+    }
+
+    pub fn name(&self, id: TimelineId) -> &str {
+        &self.names[id]
+    }
+
+    pub fn code(&self, id: TimelineId) -> Option<&[Op]> {
+        self.code[id].as_deref()
+    }
+
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.contains(label)
+    }
+}
+
+/// The name visits to a label are counted under, which can't clash with a timeline name.
+pub fn label_key(timeline_name: &str, label: &str) -> String {
+    format!("{timeline_name}#{label}")
+}
+
+/// For each loop, break and end of a loop, the position to jump to: past the end of the loop for
+/// the first two, the loop itself for the last.
+fn loop_targets(timeline: &Timeline) -> HashMap<usize, usize> {
+    let mut targets = HashMap::new();
+    let mut loops: Vec<(usize, Vec<usize>)> = Vec::new();
+
+    for (index, stmt) in timeline.iter().enumerate() {
+        match stmt {
+            Stmt::Loop { .. } => loops.push((index, vec![])),
+            Stmt::Break => {
+                if let Some((_, breaks)) = loops.last_mut() {
+                    breaks.push(index)
+                }
+            }
+            Stmt::EndLoop => {
+                let (start, breaks) = loops.pop().unwrap();
+                for position in breaks.into_iter().chain([start]) {
+                    targets.insert(position, index + 1);
+                }
+                targets.insert(index, start);
+            }
+            _ => (),
+        }
+    }
+
+    targets
+}
+
+/// For each alternatives block, the positions of its alternatives followed by the end of the
+/// block. For the end of each alternative, the end of its block.
+fn alternative_targets(timeline: &Timeline) -> HashMap<usize, Vec<usize>> {
+    let mut targets = HashMap::new();
+    let mut blocks: Vec<(usize, Vec<usize>, Vec<usize>)> = Vec::new();
+
+    for (index, stmt) in timeline.iter().enumerate() {
+        match stmt {
+            Stmt::Alternatives { .. } => blocks.push((index, vec![index + 1], vec![])),
+            Stmt::EndAlternative => {
+                let (_, starts, ends) = blocks.last_mut().unwrap();
+                starts.push(index + 1);
+                ends.push(index);
+            }
+            Stmt::EndAlternatives => {
+                let (start, mut starts, ends) = blocks.pop().unwrap();
+                // The last start is the end of the block itself.
+                starts.pop();
+                starts.push(index);
+                targets.insert(start, starts);
+                for end in ends {
+                    targets.insert(end, vec![index]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    targets
+}
+
+/// For each match, the positions of its cases followed by the end of the match. For the end of
+/// each case, the end of its match.
+fn match_targets(timeline: &Timeline) -> HashMap<usize, Vec<usize>> {
+    let mut targets = HashMap::new();
+    let mut matches: Vec<(usize, Vec<usize>, Vec<usize>)> = Vec::new();
+
+    for (index, stmt) in timeline.iter().enumerate() {
+        match stmt {
+            Stmt::Match { .. } => matches.push((index, vec![], vec![])),
+            Stmt::Case { .. } => matches.last_mut().unwrap().1.push(index),
+            Stmt::EndCase => matches.last_mut().unwrap().2.push(index),
+            Stmt::EndMatch => {
+                let (start, mut cases, ends) = matches.pop().unwrap();
+                cases.push(index);
+                targets.insert(start, cases);
+                for end in ends {
+                    targets.insert(end, vec![index]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    targets
+}
+
+/// The positions of the choices of the dialogue at `index`, and of its end.
+fn choices(timeline: &Timeline, index: usize) -> (Vec<usize>, usize) {
+    let mut next_index = index + 1;
+    let mut choices = Vec::new();
+    let mut nested_count = 0;
+
+    loop {
+        match &timeline[next_index] {
+            Stmt::EndDialogue => {
+                if nested_count > 0 {
+                    nested_count -= 1
+                } else {
+                    break;
+                }
+            }
+            Stmt::Dialogue { .. } => nested_count += 1,
+            Stmt::Choice { .. } => {
+                if nested_count > 0 {
+                    nested_count += 1
+                } else {
+                    choices.push(next_index)
+                }
+            }
+            Stmt::EndChoice => {
+                if nested_count > 0 {
+                    nested_count -= 1
+                }
+            }
+            Stmt::If { .. }
+            | Stmt::Loop { .. }
+            | Stmt::Alternatives { .. }
+            | Stmt::Match { .. } => nested_count += 1,
+            Stmt::EndIf | Stmt::EndLoop | Stmt::EndAlternatives | Stmt::EndMatch => {
+                nested_count -= 1
+            }
+            Stmt::Call { .. }
+            | Stmt::Set { .. }
+            | Stmt::Show { .. }
+            | Stmt::Hide { .. }
+            | Stmt::Move { .. }
+            | Stmt::Break
+            | Stmt::Return
+            | Stmt::End
+            | Stmt::Label { .. }
+            | Stmt::EndAlternative
+            | Stmt::Case { .. }
+            | Stmt::EndCase => (),
+        }
+        next_index += 1;
+    }
+
+    (choices, next_index)
+}
+
+/// Where to continue after the choice ending at `index`: past the other choices of its dialogue.
+fn end_of_choices(timeline: &Timeline, index: usize) -> usize {
+    let mut next_index = index + 1;
+    let mut nested_count = 0;
+
+    if matches!(timeline[next_index], Stmt::Choice { .. }) {
+        loop {
+            match &timeline[next_index] {
+                Stmt::EndDialogue => {
+                    if nested_count > 0 {
+                        nested_count -= 1
+                    } else {
+                        break;
+                    }
+                }
+                Stmt::Dialogue { .. }
+                | Stmt::Choice { .. }
+                | Stmt::If { .. }
+                | Stmt::Loop { .. }
+                | Stmt::Alternatives { .. }
+                | Stmt::Match { .. } => nested_count += 1,
+                Stmt::EndChoice
+                | Stmt::EndIf
+                | Stmt::EndLoop
+                | Stmt::EndAlternatives
+                | Stmt::EndMatch => nested_count -= 1,
+                Stmt::Call { .. }
+                | Stmt::Set { .. }
+                | Stmt::Show { .. }
+                | Stmt::Hide { .. }
+                | Stmt::Move { .. }
+                | Stmt::Break
+                | Stmt::Return
+                | Stmt::End
+                | Stmt::Label { .. }
+                | Stmt::EndAlternative
+                | Stmt::Case { .. }
+                | Stmt::EndCase => (),
+            }
+            next_index += 1;
+        }
+    }
+
+    next_index
+}
+
+/// The position of the end of the if statement at `index`.
+fn end_of_if(timeline: &Timeline, index: usize) -> usize {
+    let mut next_index = index + 1;
+    let mut nested_count = 0;
+
+    loop {
+        match &timeline[next_index] {
+            Stmt::Dialogue { .. }
+            | Stmt::Choice { .. }
+            | Stmt::If { .. }
+            | Stmt::Loop { .. }
+            | Stmt::Alternatives { .. }
+            | Stmt::Match { .. } => nested_count += 1,
+            Stmt::EndChoice
+            | Stmt::EndDialogue
+            | Stmt::EndLoop
+            | Stmt::EndAlternatives
+            | Stmt::EndMatch => nested_count -= 1,
+            Stmt::EndIf => {
+                if nested_count > 0 {
+                    nested_count -= 1
+                } else {
+                    break;
+                }
+            }
+            Stmt::Call { .. }
+            | Stmt::Set { .. }
+            | Stmt::Show { .. }
+            | Stmt::Hide { .. }
+            | Stmt::Move { .. }
+            | Stmt::Break
+            | Stmt::Return
+            | Stmt::End
+            | Stmt::Label { .. }
+            | Stmt::EndAlternative
+            | Stmt::Case { .. }
+            | Stmt::EndCase => (),
+        }
+        next_index += 1;
+    }
+
+    next_index
+}
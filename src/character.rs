@@ -1,14 +1,50 @@
+use evalexpr::Value;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, fs, path::Path};
+use walkdir::WalkDir;
 
 pub type Characters = Vec<Character>;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// A portrait drawn as a base body with an outfit and an expression on top.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PortraitLayers {
+    pub base: String,
+    #[serde(default)]
+    pub outfits: HashMap<String, String>,
+    #[serde(default)]
+    pub expressions: HashMap<String, String>,
+    #[serde(default)]
+    pub default_outfit: Option<String>,
+    #[serde(default)]
+    pub default_expression: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Character {
     id: String,
     display_name: String,
+    #[serde(default)]
     aliases: HashMap<String, String>,
+    /// Single-image portraits, used if the character has no `layers`.
+    #[serde(default)]
     portraits: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    layers: Option<PortraitLayers>,
+    /// Colour of the nameplate, e.g. `"#ff8800"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_color: Option<String>,
+    /// Multiplier of the host's default text speed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text_speed: Option<f64>,
+    /// Sound played while the character's text is typed out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    voice_blip: Option<String>,
+    /// Free-form data for the host.
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    metadata: serde_json::Value,
+    /// Default values of the character's script variables, e.g. `Elira.affection`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    variables: HashMap<String, serde_json::Value>,
 }
 
 impl Character {
@@ -23,6 +59,12 @@ impl Character {
             display_name: display_name.to_owned(),
             aliases,
             portraits,
+            layers: None,
+            name_color: None,
+            text_speed: None,
+            voice_blip: None,
+            metadata: serde_json::Value::Null,
+            variables: HashMap::new(),
         }
     }
 
@@ -47,4 +89,312 @@ impl Character {
     pub fn portraits(&self) -> &HashMap<String, String> {
         &self.portraits
     }
+
+    pub fn layers(&self) -> Option<&PortraitLayers> {
+        self.layers.as_ref()
+    }
+
+    pub fn set_layers(&mut self, layers: Option<PortraitLayers>) {
+        self.layers = layers
+    }
+
+    pub fn has_outfit(&self, outfit: &str) -> bool {
+        self.layers
+            .as_ref()
+            .is_some_and(|layers| layers.outfits.contains_key(outfit))
+    }
+
+    /// The names usable as an expression in scripts: portraits, outfits and expressions.
+    pub fn portrait_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.portraits.keys().map(String::as_str).collect();
+        if let Some(layers) = &self.layers {
+            names.extend(layers.outfits.keys().map(String::as_str));
+            names.extend(layers.expressions.keys().map(String::as_str));
+        }
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// The image paths of the character's portrait, from bottom to top.
+    ///
+    /// Unknown expressions and outfits fall back to the defaults. Without layers, this is the
+    /// single portrait named `expression`, or `default`.
+    pub fn portrait_layers(&self, expression: Option<&str>, outfit: Option<&str>) -> Vec<String> {
+        match &self.layers {
+            Some(layers) => {
+                let mut paths = vec![layers.base.to_owned()];
+                paths.extend(pick_layer(&layers.outfits, outfit, &layers.default_outfit));
+                paths.extend(pick_layer(
+                    &layers.expressions,
+                    expression,
+                    &layers.default_expression,
+                ));
+                paths
+            }
+            None => self
+                .get_portrait_path(expression.unwrap_or("default"))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn name_color(&self) -> Option<&String> {
+        self.name_color.as_ref()
+    }
+
+    pub fn text_speed(&self) -> Option<f64> {
+        self.text_speed
+    }
+
+    pub fn voice_blip(&self) -> Option<&String> {
+        self.voice_blip.as_ref()
+    }
+
+    pub fn metadata(&self) -> &serde_json::Value {
+        &self.metadata
+    }
+
+    pub fn has_variable(&self, variable_name: &str) -> bool {
+        self.variables.contains_key(variable_name)
+    }
+
+    /// The default values of the character's variables, keyed by their script names, e.g.
+    /// `Elira.affection`.
+    pub fn variables(&self) -> HashMap<String, Value> {
+        self.variables
+            .iter()
+            .map(|(name, value)| (format!("{}.{name}", self.id), json_to_value(value)))
+            .collect()
+    }
+}
+
+fn pick_layer(
+    images: &HashMap<String, String>,
+    name: Option<&str>,
+    default: &Option<String>,
+) -> Option<String> {
+    name.and_then(|name| images.get(name))
+        .or_else(|| default.as_ref().and_then(|name| images.get(name)))
+        .cloned()
+}
+
+fn json_to_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Empty,
+        serde_json::Value::Bool(v) => Value::Boolean(*v),
+        serde_json::Value::Number(v) => match v.as_i64() {
+            Some(v) => Value::Int(v),
+            None => Value::Float(v.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(v) => Value::String(v.to_owned()),
+        serde_json::Value::Array(values) => {
+            Value::Tuple(values.iter().map(json_to_value).collect())
+        }
+        serde_json::Value::Object(_) => Value::String(value.to_string()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CharacterError {
+    Read {
+        path: String,
+        message: String,
+    },
+    DuplicateId {
+        id: String,
+    },
+    AliasCollision {
+        alias: String,
+        character_id: String,
+        other_id: String,
+    },
+    MissingPortrait {
+        character_id: String,
+        portrait: String,
+        path: String,
+    },
+}
+
+impl fmt::Display for CharacterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CharacterError::Read { path, message } => write!(f, "Couldn't read '{path}': {message}"),
+            CharacterError::DuplicateId { id } => write!(f, "Character '{id}' is defined more than once."),
+            CharacterError::AliasCollision {
+                alias,
+                character_id,
+                other_id,
+            } => write!(
+                f,
+                "Alias '{alias}' of character '{character_id}' collides with character '{other_id}'."
+            ),
+            CharacterError::MissingPortrait {
+                character_id,
+                portrait,
+                path,
+            } => write!(
+                f,
+                "Portrait '{portrait}' of character '{character_id}' not found at '{path}'."
+            ),
+        }
+    }
+}
+
+/// The characters of a story, usually loaded from one file per character.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CharacterRegistry {
+    characters: Characters,
+}
+
+impl CharacterRegistry {
+    pub fn new(characters: Characters) -> Self {
+        CharacterRegistry { characters }
+    }
+
+    /// Loads every `.json`, `.toml`, `.yaml` and `.yml` file under `dir_name` as one character
+    /// and validates them, with portrait paths relative to `asset_dir`.
+    ///
+    /// Returns every issue found instead of stopping at the first one.
+    pub fn from_dir(dir_name: &str, asset_dir: &str) -> Result<Self, Vec<CharacterError>> {
+        let mut paths: Vec<_> = WalkDir::new(dir_name)
+            .into_iter()
+            .filter_map(|v| v.ok())
+            .filter(|x| x.file_type().is_file())
+            .map(|x| x.into_path())
+            .collect();
+        paths.sort();
+
+        let mut characters = Vec::new();
+        let mut errors = Vec::new();
+        for path in paths {
+            match load_character(&path) {
+                Some(Ok(character)) => characters.push(character),
+                Some(Err(message)) => errors.push(CharacterError::Read {
+                    path: path.to_string_lossy().into_owned(),
+                    message,
+                }),
+                None => (),
+            }
+        }
+
+        let registry = CharacterRegistry::new(characters);
+        errors.append(&mut registry.validate(asset_dir));
+        if errors.is_empty() {
+            Ok(registry)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Checks for duplicate IDs, aliases that collide with other IDs or aliases, and portraits
+    /// missing from `asset_dir`.
+    pub fn validate(&self, asset_dir: &str) -> Vec<CharacterError> {
+        let mut errors = Vec::new();
+        // Every name a character can be referred to by in scripts, and who it belongs to.
+        let mut names: HashMap<&str, &str> = HashMap::new();
+
+        for character in &self.characters {
+            if names.insert(character.id(), character.id()).is_some() {
+                errors.push(CharacterError::DuplicateId {
+                    id: character.id().to_owned(),
+                });
+            }
+        }
+
+        for character in &self.characters {
+            let mut aliases: Vec<&String> = character.aliases.keys().collect();
+            aliases.sort();
+            for alias in aliases {
+                match names.get(alias.as_str()) {
+                    Some(other_id) if *other_id != character.id() || alias != character.id() => {
+                        errors.push(CharacterError::AliasCollision {
+                            alias: alias.to_owned(),
+                            character_id: character.id().to_owned(),
+                            other_id: other_id.to_string(),
+                        })
+                    }
+                    Some(_) => (),
+                    None => {
+                        names.insert(alias, character.id());
+                    }
+                }
+            }
+
+            let mut portraits: Vec<(String, &String)> = character
+                .portraits
+                .iter()
+                .map(|(name, path)| (name.to_owned(), path))
+                .collect();
+            if let Some(layers) = &character.layers {
+                portraits.push(("base".to_owned(), &layers.base));
+                portraits.extend(
+                    layers
+                        .outfits
+                        .iter()
+                        .map(|(name, path)| (format!("outfits.{name}"), path)),
+                );
+                portraits.extend(
+                    layers
+                        .expressions
+                        .iter()
+                        .map(|(name, path)| (format!("expressions.{name}"), path)),
+                );
+            }
+            portraits.sort();
+            for (portrait, path) in portraits {
+                if !Path::new(asset_dir).join(path).is_file() {
+                    errors.push(CharacterError::MissingPortrait {
+                        character_id: character.id().to_owned(),
+                        portrait,
+                        path: path.to_owned(),
+                    });
+                }
+            }
+        }
+
+        errors
+    }
+
+    pub fn get(&self, character_id: &str) -> Option<&Character> {
+        self.characters.iter().find(|c| c.id() == character_id)
+    }
+
+    /// Adds a character, replacing the one with the same ID if there's one.
+    pub fn insert(&mut self, character: Character) {
+        match self
+            .characters
+            .iter_mut()
+            .find(|c| c.id() == character.id())
+        {
+            Some(c) => *c = character,
+            None => self.characters.push(character),
+        }
+    }
+
+    pub fn characters(&self) -> &Characters {
+        &self.characters
+    }
+
+    pub fn into_characters(self) -> Characters {
+        self.characters
+    }
+}
+
+/// Reads a character file, or returns `None` if it isn't in a supported format.
+fn load_character(path: &Path) -> Option<Result<Character, String>> {
+    let extension = path.extension()?.to_str()?;
+    if !matches!(extension, "json" | "toml" | "yaml" | "yml") {
+        return None;
+    }
+
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => return Some(Err(e.to_string())),
+    };
+    Some(match extension {
+        "json" => serde_json::from_str(&contents).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => serde_yaml_ng::from_str(&contents).map_err(|e| e.to_string()),
+    })
 }
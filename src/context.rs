@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use evalexpr::{Context, EvalexprError, EvalexprResult, HashMapContext, Value};
+
+use crate::{
+    bytecode::{label_key, Program},
+    plural,
+    random::{self, Rng},
+};
+
+/// The context expressions are evaluated in: the locals of the running timeline, the variables of
+/// the characters, the host's variables and functions, and the built-in functions of the language.
+pub(crate) struct ScriptContext<'a> {
+    pub locals: Option<&'a HashMap<String, Value>>,
+    pub variables: &'a HashMap<String, Value>,
+    pub context: &'a HashMapContext,
+    pub locale: &'a str,
+    /// The name of the running timeline, which labels are looked up in first.
+    pub timeline: Option<&'a str>,
+    pub visits: &'a HashMap<String, u64>,
+    pub program: &'a Program,
+    pub rng: &'a Rng,
+}
+
+impl<'a> ScriptContext<'a> {
+    /// The visits to a label of the running timeline, or else to a timeline or a label by its
+    /// full name, e.g. `tavern#bar`.
+    fn visits(&self, argument: &Value) -> EvalexprResult<u64> {
+        let name = argument.as_string()?;
+        let key = self
+            .timeline
+            .map(|timeline| label_key(timeline, &name))
+            .filter(|label| self.program.has_label(label))
+            .unwrap_or(name);
+        Ok(self.visits.get(&key).copied().unwrap_or_default())
+    }
+}
+
+impl<'a> Context for ScriptContext<'a> {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        self.locals
+            .and_then(|locals| locals.get(identifier))
+            .or_else(|| self.variables.get(identifier))
+            .or_else(|| self.context.get_value(identifier))
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        match self.context.call_function(identifier, argument) {
+            Err(EvalexprError::FunctionIdentifierNotFound(_)) => match identifier {
+                "plural" => plural::plural(self.locale, argument),
+                "pronoun" => plural::pronoun(argument),
+                "visits" => Ok(Value::Int(self.visits(argument)? as i64)),
+                "seen" => Ok(Value::Boolean(self.visits(argument)? > 0)),
+                "random" => random::random(self.rng, argument),
+                "chance" => random::chance(self.rng, argument),
+                _ => Err(EvalexprError::FunctionIdentifierNotFound(
+                    identifier.to_owned(),
+                )),
+            },
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Format specifiers in templates, e.g. `{gold:,}`, `{ratio:.1%}`, `{name:upper}` or
+//! `{count:02}`.
+//!
+//! A spec is either the name of a formatter (`upper`, `lower`, `title` or one registered by the
+//! host) or a number format: `[0][width][,][.precision][%]`.
+
+use evalexpr::Value;
+
+pub type Formatter = Box<dyn Fn(&Value) -> Result<String, String> + Send + Sync>;
+
+/// Splits a template into its expression and format spec at the last `:` outside of a string.
+pub fn split_spec(template: &str) -> (&str, Option<&str>) {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut split = None;
+
+    for (i, c) in template.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ':' if !in_string => split = Some(i),
+            _ => (),
+        }
+    }
+
+    match split {
+        Some(i) => (template[..i].trim(), Some(template[i + 1..].trim())),
+        None => (template.trim(), None),
+    }
+}
+
+/// Formats `value` with one of the built-in specs, or as is without one.
+pub fn format_value(value: &Value, spec: Option<&str>) -> Result<String, String> {
+    let spec = match spec {
+        Some(spec) => spec,
+        None => {
+            return Ok(match value {
+                Value::String(v) => v.to_owned(),
+                Value::Float(v) => v.to_string(),
+                Value::Int(v) => v.to_string(),
+                Value::Boolean(v) => v.to_string(),
+                Value::Tuple(values) => values
+                    .iter()
+                    .map(|v| format_value(v, None))
+                    .collect::<Result<Vec<String>, String>>()?
+                    .join(", "),
+                Value::Empty => "".to_owned(),
+            })
+        }
+    };
+
+    match spec {
+        "upper" => Ok(format_value(value, None)?.to_uppercase()),
+        "lower" => Ok(format_value(value, None)?.to_lowercase()),
+        "title" => Ok(title_case(&format_value(value, None)?)),
+        _ => format_number(value, spec),
+    }
+}
+
+fn title_case(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut start = true;
+    for c in text.chars() {
+        if start {
+            output.extend(c.to_uppercase());
+        } else {
+            output.push(c);
+        }
+        start = c.is_whitespace();
+    }
+    output
+}
+
+struct NumberSpec {
+    zero_pad: bool,
+    width: usize,
+    grouping: bool,
+    precision: Option<usize>,
+    percent: bool,
+}
+
+impl NumberSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        let mut rest = spec;
+        let percent = match rest.strip_suffix('%') {
+            Some(r) => {
+                rest = r;
+                true
+            }
+            None => false,
+        };
+        let precision = match rest.split_once('.') {
+            Some((r, precision)) => {
+                rest = r;
+                Some(precision.parse().ok()?)
+            }
+            None => None,
+        };
+        let grouping = match rest.strip_suffix(',') {
+            Some(r) => {
+                rest = r;
+                true
+            }
+            None => false,
+        };
+        let zero_pad = rest.starts_with('0');
+        let width = if rest.is_empty() {
+            0
+        } else {
+            rest.parse().ok()?
+        };
+
+        Some(NumberSpec {
+            zero_pad,
+            width,
+            grouping,
+            precision,
+            percent,
+        })
+    }
+}
+
+fn format_number(value: &Value, spec: &str) -> Result<String, String> {
+    let number_spec = NumberSpec::parse(spec).ok_or(format!("Unknown format '{spec}'."))?;
+    let mut number = match value {
+        Value::Int(v) => *v as f64,
+        Value::Float(v) => *v,
+        _ => {
+            return Err(format!(
+                "Format '{spec}' expects a number, found '{value}'."
+            ))
+        }
+    };
+    if number_spec.percent {
+        number *= 100.0;
+    }
+
+    let mut digits = match (number_spec.precision, value) {
+        (Some(precision), _) => format!("{:.*}", precision, number.abs()),
+        (None, Value::Int(v)) if !number_spec.percent => v.unsigned_abs().to_string(),
+        (None, _) => number.abs().to_string(),
+    };
+
+    if number_spec.grouping {
+        let (integer, fraction) = match digits.split_once('.') {
+            Some((integer, fraction)) => (integer.to_owned(), format!(".{fraction}")),
+            None => (digits.to_owned(), String::new()),
+        };
+        let mut grouped = String::new();
+        for (i, c) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                grouped.push(',');
+            }
+            grouped.push(c);
+        }
+        digits = grouped + &fraction;
+    }
+
+    let sign = if number < 0.0 { "-" } else { "" };
+    let suffix = if number_spec.percent { "%" } else { "" };
+    let len = sign.len() + digits.len() + suffix.len();
+    let padding = number_spec.width.saturating_sub(len);
+
+    Ok(if number_spec.zero_pad {
+        format!("{sign}{}{digits}{suffix}", "0".repeat(padding))
+    } else {
+        format!("{}{sign}{digits}{suffix}", " ".repeat(padding))
+    })
+}
@@ -1,11 +1,21 @@
 #[macro_use]
 extern crate pest_derive;
 
+pub mod bundle;
+pub mod bytecode;
 mod character;
+mod context;
+pub mod format;
+pub mod markup;
 pub mod parser;
-pub use character::Character;
+pub mod plural;
+mod random;
+pub use character::{Character, CharacterError, CharacterRegistry, PortraitLayers};
 pub mod server;
+pub mod tagger;
+pub mod template;
 #[cfg(test)]
 pub mod test;
+pub mod voice;
 
 pub const FILE_EXTENSION: &str = "nobela";
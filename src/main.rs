@@ -1 +1,87 @@
-fn main() {}
+use std::{env, process};
+
+use nobela::{
+    bundle::Bundle,
+    parser::{characters_from_json, ParseState, Parser},
+    tagger,
+    voice::{self, DEFAULT_VOICE_PATTERN},
+};
+
+const USAGE: &str = "Usage:
+    nobela build <dir> <characters.json> <output>
+    nobela tag-lines <dir>
+    nobela check-voice <dir> <characters.json> <asset_dir> [pattern]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["tag-lines", dir_name] => match tagger::tag_dir(dir_name) {
+            Ok(count) => println!("Tagged {count} line(s)."),
+            Err(e) => fail(&e.to_string()),
+        },
+        ["build", dir_name, characters, output] => build(dir_name, characters, output),
+        ["check-voice", dir_name, characters, asset_dir] => {
+            check_voice(dir_name, characters, asset_dir, DEFAULT_VOICE_PATTERN)
+        }
+        ["check-voice", dir_name, characters, asset_dir, pattern] => {
+            check_voice(dir_name, characters, asset_dir, pattern)
+        }
+        _ => fail(USAGE),
+    }
+}
+
+fn build(dir_name: &str, characters: &str, output: &str) {
+    let characters = characters_from_json(characters).unwrap_or_else(|e| fail(&e.to_string()));
+    let parser = Parser::new(characters.to_owned());
+    let mut state = ParseState::default();
+    let timelines = parser
+        .parse_dir_with(dir_name, &mut state)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    print_warnings(&state);
+    let count = timelines.len();
+
+    Bundle::new(timelines, characters)
+        .save(output)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    println!("Bundled {count} timeline(s) into {output}.");
+}
+
+fn check_voice(dir_name: &str, characters: &str, asset_dir: &str, pattern: &str) {
+    let characters = characters_from_json(characters).unwrap_or_else(|e| fail(&e.to_string()));
+    let parser = Parser::new(characters);
+    let mut state = ParseState::default();
+    let timelines = parser
+        .parse_dir_with(dir_name, &mut state)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    print_warnings(&state);
+    let missing = voice::missing_voice_files(&timelines, pattern, asset_dir);
+
+    for line in &missing {
+        let line_id = line.line_id.as_deref().unwrap_or("untagged");
+        match &line.voice_path {
+            Some(voice_path) => println!(
+                "{}:{} ({line_id}): {voice_path}",
+                line.timeline_name, line.index
+            ),
+            None => println!(
+                "{}:{} ({line_id}): no voice path",
+                line.timeline_name, line.index
+            ),
+        }
+    }
+    if !missing.is_empty() {
+        fail(&format!("{} line(s) missing voice files.", missing.len()));
+    }
+}
+
+fn print_warnings(state: &ParseState) {
+    for warning in &state.warnings {
+        eprintln!("warning: {warning}");
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    process::exit(1)
+}
@@ -0,0 +1,212 @@
+//! Inline markup in dialogue text, e.g. `"I [b]really[/b] mean it[wait=0.5]..."`.
+//!
+//! `[name]` or `[name=value]` opens a style that lasts until `[/name]`. Tags that are never
+//! closed are inline control markers. `[[` is a literal `[`. Unknown tags are passed through
+//! to the host as is.
+
+/// Styles that must be closed.
+pub const STYLE_TAGS: [&str; 7] = ["b", "i", "u", "s", "color", "size", "speed"];
+/// Markers that can't be closed.
+pub const CONTROL_TAGS: [&str; 1] = ["wait"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Span {
+    Text { text: String, styles: Vec<Tag> },
+    Control(Tag),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MarkupError {
+    pub message: String,
+    /// Byte offset of the offending tag in the text.
+    pub offset: usize,
+}
+
+enum Token {
+    Text(String),
+    Open(Tag, usize),
+    Close(String, usize),
+}
+
+impl Tag {
+    fn check(&self, offset: usize) -> Result<(), MarkupError> {
+        let error = |message: String| Err(MarkupError { message, offset });
+        let requires_number = matches!(self.name.as_str(), "wait" | "speed" | "size");
+        let requires_value = requires_number || self.name == "color";
+
+        match &self.value {
+            None if requires_value => error(format!("Tag '{}' requires a value.", self.name)),
+            Some(value) if requires_number && value.parse::<f64>().is_err() => error(format!(
+                "Tag '{}' expects a number, found '{value}'.",
+                self.name
+            )),
+            Some(_) if STYLE_TAGS[..4].contains(&self.name.as_str()) => {
+                error(format!("Tag '{}' doesn't take a value.", self.name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, MarkupError> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('[') {
+        literal.push_str(&rest[..start]);
+        let offset = text.len() - rest.len() + start;
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('[') {
+            literal.push('[');
+            rest = after;
+            continue;
+        }
+
+        let end = rest.find(']').ok_or(MarkupError {
+            message: "Unclosed '['. Use '[[' for a literal bracket.".to_owned(),
+            offset,
+        })?;
+        let content = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let (closing, content) = match content.strip_prefix('/') {
+            Some(content) => (true, content),
+            None => (false, content),
+        };
+        let (name, value) = match content.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_owned())),
+            None => (content.trim(), None),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(MarkupError {
+                message: format!("Invalid tag '[{}]'.", &text[offset + 1..offset + 1 + end]),
+                offset,
+            });
+        }
+
+        if !literal.is_empty() {
+            tokens.push(Token::Text(literal));
+            literal = String::new();
+        }
+        if closing {
+            if value.is_some() {
+                return Err(MarkupError {
+                    message: format!("Closing tag '[/{name}]' can't have a value."),
+                    offset,
+                });
+            }
+            tokens.push(Token::Close(name.to_owned(), offset));
+        } else {
+            let tag = Tag {
+                name: name.to_owned(),
+                value,
+            };
+            tag.check(offset)?;
+            tokens.push(Token::Open(tag, offset));
+        }
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        tokens.push(Token::Text(literal));
+    }
+    Ok(tokens)
+}
+
+/// Splits `text` into styled text spans and control markers.
+pub fn parse(text: &str) -> Result<Vec<Span>, MarkupError> {
+    let tokens = tokenize(text)?;
+
+    // Work out which opening tags are styles (closed later on) and which are controls.
+    let mut is_style = vec![false; tokens.len()];
+    let mut open: Vec<usize> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Open(tag, _) if !CONTROL_TAGS.contains(&tag.name.as_str()) => open.push(i),
+            Token::Close(name, offset) => {
+                let position = open.iter().rposition(|&j| match &tokens[j] {
+                    Token::Open(tag, _) => tag.name == *name,
+                    _ => unreachable!(),
+                });
+                let position = position.ok_or(MarkupError {
+                    message: format!("Closing tag '[/{name}]' has no matching opening tag."),
+                    offset: *offset,
+                })?;
+                for &j in &open[position + 1..] {
+                    if let Token::Open(tag, _) = &tokens[j] {
+                        if STYLE_TAGS.contains(&tag.name.as_str()) {
+                            return Err(MarkupError {
+                                message: format!(
+                                    "Tag '[{}]' must be closed before '[/{name}]'.",
+                                    tag.name
+                                ),
+                                offset: *offset,
+                            });
+                        }
+                    }
+                }
+                is_style[open[position]] = true;
+                open.truncate(position);
+            }
+            _ => (),
+        }
+    }
+    for j in open {
+        if let Token::Open(tag, offset) = &tokens[j] {
+            if STYLE_TAGS.contains(&tag.name.as_str()) {
+                return Err(MarkupError {
+                    message: format!("Tag '[{}]' is never closed.", tag.name),
+                    offset: *offset,
+                });
+            }
+        }
+    }
+
+    let mut spans: Vec<Span> = Vec::new();
+    let mut styles: Vec<Tag> = Vec::new();
+    for (i, token) in tokens.into_iter().enumerate() {
+        match token {
+            Token::Text(text) => match spans.last_mut() {
+                Some(Span::Text {
+                    text: last_text,
+                    styles: last_styles,
+                }) if *last_styles == styles => last_text.push_str(&text),
+                _ => spans.push(Span::Text {
+                    text,
+                    styles: styles.to_owned(),
+                }),
+            },
+            Token::Open(tag, _) if is_style[i] => styles.push(tag),
+            Token::Open(tag, _) => spans.push(Span::Control(tag)),
+            Token::Close(name, _) => {
+                let position = styles.iter().rposition(|tag| tag.name == name).unwrap();
+                styles.remove(position);
+            }
+        }
+    }
+
+    Ok(spans)
+}
+
+/// Concatenates the text of `spans`, leaving out all markup.
+pub fn plain_text(spans: &[Span]) -> String {
+    spans
+        .iter()
+        .filter_map(|span| match span {
+            Span::Text { text, .. } => Some(text.as_str()),
+            Span::Control(_) => None,
+        })
+        .collect()
+}
@@ -1,14 +1,20 @@
+use evalexpr::Value;
+use serde::{Deserialize, Serialize};
 use serde_json::Result as SerdeResult;
-use std::{collections::HashMap, ffi::OsStr, fs, path::Path};
+use std::{ffi::OsStr, fs, path::Path};
 
 use pest::{
+    error::ErrorVariant,
     iterators::{Pair, Pairs},
-    Parser as PestParser, RuleType,
+    Parser as PestParser, Position, RuleType, Span,
 };
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 use crate::{
+    markup,
     server::{Timeline, Timelines},
+    tagger,
+    template::{self, Alternation},
     Character, FILE_EXTENSION,
 };
 
@@ -18,19 +24,27 @@ use super::character::Characters;
 #[grammar = "nobela.pest"]
 pub struct ScriptParser;
 
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Stmt {
     Dialogue {
         character_id: Option<String>,
         speaker: Option<String>,
         text: String,
         expression: Option<String>,
-        portraits: HashMap<String, String>,
+        line_id: Option<String>,
+        voice: Option<String>,
     },
     EndDialogue,
     Choice {
         text: String,
         condition: Option<String>,
+        line_id: Option<String>,
+        /// Hidden once it has been chosen.
+        #[serde(default)]
+        once: bool,
+        /// Chosen automatically when all the other choices are hidden. Has no text.
+        #[serde(default)]
+        fallback: bool,
     },
     EndChoice,
     If {
@@ -40,11 +54,139 @@ pub enum Stmt {
     Call {
         jump: bool,
         timeline_name: String,
+        /// The names and expressions of the arguments, which become locals of the timeline.
+        #[serde(default)]
+        arguments: Vec<(String, String)>,
     },
     Set {
         variable_name: String,
         expression: String,
     },
+    Show {
+        character_id: String,
+        expression: Option<String>,
+        position: Option<String>,
+    },
+    Hide {
+        character_id: String,
+    },
+    Move {
+        character_id: String,
+        position: String,
+    },
+    /// Repeats its block while the condition holds, or until a `break` without one.
+    Loop {
+        condition: Option<String>,
+    },
+    EndLoop,
+    /// Leaves the innermost loop.
+    Break,
+    /// Leaves the timeline, back to the one that called it.
+    Return,
+    /// Ends the story.
+    End,
+    /// A named point in a timeline whose visits are counted.
+    Label {
+        name: String,
+    },
+    /// Runs one of the statements of its block, each ended by [`Stmt::EndAlternative`].
+    Alternatives {
+        alternation: Alternation,
+    },
+    EndAlternative,
+    EndAlternatives,
+    /// Runs the first case whose value equals the expression, or the default case.
+    Match {
+        expression: String,
+    },
+    /// A case of a match: a literal, or `None` for `default`. Ended by [`Stmt::EndCase`].
+    Case {
+        value: Option<String>,
+    },
+    EndCase,
+    EndMatch,
+}
+
+/// The path a file is compared by when looking for include cycles.
+fn include_key(filename: &str) -> String {
+    fs::canonicalize(filename)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| filename.to_owned())
+}
+
+/// The name in `names` closest to `name`, if it's close enough to be a likely typo.
+fn closest_match<'a>(name: &str, names: &[&'a str]) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(2);
+    names
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Joins the lines of a triple-quoted text starting at `start` in the input. Also returns the
+/// position in the input of each byte of the result and of its end, so errors point at the
+/// right column.
+fn join_lines(contents: &str, start: usize, line_join: LineJoin) -> (String, Vec<usize>) {
+    let mut lines = Vec::new();
+    let mut offset = start;
+    for line in contents.split('\n') {
+        let trimmed = line.trim_start();
+        lines.push((trimmed.trim_end(), offset + line.len() - trimmed.len()));
+        offset += line.len() + 1;
+    }
+    let first = lines.iter().position(|(line, _)| !line.is_empty());
+    let last = lines.iter().rposition(|(line, _)| !line.is_empty());
+    let lines = match (first, last) {
+        (Some(first), Some(last)) => &lines[first..=last],
+        _ => &[][..],
+    };
+
+    let mut text = String::new();
+    let mut positions = Vec::new();
+    let mut blank = false;
+    for (index, (line, offset)) in lines.iter().enumerate() {
+        if index > 0 {
+            let separator = match line_join {
+                LineJoin::Fold if line.is_empty() => {
+                    blank = true;
+                    continue;
+                }
+                LineJoin::Fold if !blank => " ",
+                LineJoin::Fold | LineJoin::Keep => "\n",
+            };
+            text.push_str(separator);
+            // Just before the line.
+            positions.push(offset - 1);
+            blank = false;
+        }
+        text.push_str(line);
+        positions.extend(*offset..offset + line.len());
+    }
+    positions.push(match lines.last() {
+        Some((line, offset)) => offset + line.len(),
+        None => start,
+    });
+
+    (text, positions)
 }
 
 pub fn characters_from_json(path: &str) -> SerdeResult<Vec<Character>> {
@@ -53,36 +195,149 @@ pub fn characters_from_json(path: &str) -> SerdeResult<Vec<Character>> {
     serde_json::from_str(&contents)
 }
 
+pub(crate) fn script_entries(dir_name: &str) -> impl Iterator<Item = DirEntry> {
+    WalkDir::new(dir_name)
+        .into_iter()
+        .filter_map(|v| v.ok())
+        .filter(|x| x.path().extension().unwrap_or_else(|| OsStr::new("")) == FILE_EXTENSION)
+}
+
+/// Whether a script is only meant to be included: it or a directory it's in, below `dir_name`,
+/// starts with `_`.
+fn is_fragment(entry: &DirEntry, dir_name: &str) -> bool {
+    entry
+        .path()
+        .strip_prefix(dir_name)
+        .unwrap_or(entry.path())
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('_'))
+}
+
+/// How the lines of a triple-quoted text are joined. Lines are trimmed either way, and blank
+/// lines at its start and end are dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineJoin {
+    /// Lines are joined with spaces, and blank lines start a new line, as in Markdown.
+    #[default]
+    Fold,
+    /// Line breaks are kept.
+    Keep,
+}
+
+/// What is kept track of while parsing a script and the files it includes.
+#[derive(Debug, Default)]
+pub struct ParseState {
+    /// Overrides the parser's script directory, e.g. with the one [`Parser::parse_dir`] parses.
+    script_dir: Option<String>,
+    /// The files being parsed, the innermost last, with the paths they're compared by.
+    include_stack: Vec<(String, String)>,
+    /// How many loops the statement being parsed is in.
+    loop_depth: usize,
+    /// Issues that don't stop parsing, e.g. duplicate cases.
+    pub warnings: Vec<pest::error::Error<Rule>>,
+}
+
+impl ParseState {
+    fn warn(&mut self, message: String, span: Span) {
+        let warning =
+            pest::error::Error::new_from_span(ErrorVariant::CustomError { message }, span);
+        let warning = match self.include_stack.last() {
+            Some((filename, _)) => warning.with_path(filename),
+            None => warning,
+        };
+        self.warnings.push(warning);
+    }
+}
+
 pub struct Parser {
     characters: Characters,
+    line_join: LineJoin,
+    /// The directory `include` paths are relative to.
+    script_dir: Option<String>,
 }
 impl Parser {
     pub fn new(characters: Characters) -> Self {
-        Parser { characters }
+        Parser {
+            characters,
+            line_join: LineJoin::default(),
+            script_dir: None,
+        }
+    }
+
+    pub fn set_line_join(&mut self, line_join: LineJoin) {
+        self.line_join = line_join
+    }
+
+    /// Sets the directory `include "a.b"` looks for `a/b.nobela` in. [`Parser::parse_dir`] uses
+    /// the directory it parses.
+    pub fn set_script_dir(&mut self, script_dir: &str) {
+        self.script_dir = Some(script_dir.to_owned())
     }
 
     pub fn document<'a>(
         &'a self,
         input: &'a str,
-    ) -> Result<Pairs<'_, Rule>, pest::error::Error<Rule>> {
+    ) -> Result<Pairs<'a, Rule>, pest::error::Error<Rule>> {
         ScriptParser::parse(Rule::document, input)
     }
 
     pub fn parse_file(&self, filename: &str) -> Result<Timeline, pest::error::Error<Rule>> {
+        self.parse_file_with(filename, &mut ParseState::default())
+    }
+
+    /// Like [`Parser::parse_file`], collecting warnings in `state`.
+    pub fn parse_file_with(
+        &self,
+        filename: &str,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let contents = fs::read_to_string(filename)
             .unwrap_or_else(|_| panic!("Something went wrong reading '{filename}'."));
-        self.parse(&contents)
+
+        state
+            .include_stack
+            .push((filename.to_owned(), include_key(filename)));
+        let result = self.parse_with(&contents, state);
+        state.include_stack.pop();
+
+        // Errors in included files already have their path.
+        result.map_err(|e| match e.path() {
+            Some(_) => e,
+            None => e.with_path(filename),
+        })
     }
 
+    /// Parses the scripts in a directory into timelines named by their path, e.g. `a.b` for
+    /// `a/b.nobela`. Files and directories starting with `_`, e.g. `_common/shop.nobela`, only
+    /// hold scripts to include and aren't timelines themselves. Line IDs must be unique across
+    /// the directory.
     pub fn parse_dir(&self, dir_name: &str) -> Result<Timelines, pest::error::Error<Rule>> {
+        self.parse_dir_with(dir_name, &mut ParseState::default())
+    }
+
+    /// Like [`Parser::parse_dir`], collecting warnings in `state`.
+    pub fn parse_dir_with(
+        &self,
+        dir_name: &str,
+        state: &mut ParseState,
+    ) -> Result<Timelines, pest::error::Error<Rule>> {
+        let script_dir = state.script_dir.replace(dir_name.to_owned());
+        let timelines = self.parse_dir_entries(dir_name, state);
+        state.script_dir = script_dir;
+        let timelines = timelines?;
+        tagger::check_line_ids(dir_name)?;
+        Ok(timelines)
+    }
+
+    fn parse_dir_entries(
+        &self,
+        dir_name: &str,
+        state: &mut ParseState,
+    ) -> Result<Timelines, pest::error::Error<Rule>> {
         let mut timelines = Timelines::new();
-        let entries = WalkDir::new(dir_name)
-            .into_iter()
-            .filter_map(|v| v.ok())
-            .filter(|x| x.path().extension().unwrap_or_else(|| OsStr::new("")) == FILE_EXTENSION);
 
-        for entry in entries {
-            let timeline = self.parse_file(entry.path().to_str().unwrap())?;
+        for entry in script_entries(dir_name).filter(|entry| !is_fragment(entry, dir_name)) {
+            let timeline = self.parse_file_with(entry.path().to_str().unwrap(), state)?;
             let name = entry
                 .path()
                 .to_str()
@@ -92,8 +347,7 @@ impl Parser {
                 .unwrap()
                 .strip_prefix(&dir_name.to_string())
                 .unwrap()
-                .replace('\\', ".")
-                .replace('/', ".")
+                .replace(['\\', '/'], ".")
                 .strip_prefix('.')
                 .unwrap()
                 .to_owned();
@@ -104,11 +358,20 @@ impl Parser {
     }
 
     pub fn parse(&self, input: &str) -> Result<Timeline, pest::error::Error<Rule>> {
+        self.parse_with(input, &mut ParseState::default())
+    }
+
+    /// Like [`Parser::parse`], collecting warnings in `state`.
+    pub fn parse_with(
+        &self,
+        input: &str,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let pairs = self.document(input)?;
         let mut statements = Vec::new();
 
         for pair in pairs {
-            statements.append(&mut self.events_pair(pair))
+            statements.append(&mut self.events_pair(pair, state)?)
         }
 
         Ok(statements)
@@ -119,7 +382,137 @@ impl Parser {
         str[1..str.len() - 1].to_owned()
     }
 
-    pub fn dialogue_pair(&self, pair: Pair<Rule>) -> Timeline {
+    /// The contents of a `text` pair, and the position in the input of each of their bytes and
+    /// of their end.
+    fn get_text(&self, pair: Pair<Rule>) -> (String, Vec<usize>) {
+        let inner_pair = pair.into_inner().next().unwrap();
+        match inner_pair.as_rule() {
+            Rule::block_string => {
+                let contents = inner_pair.into_inner().next().unwrap();
+                join_lines(
+                    contents.as_str(),
+                    contents.as_span().start(),
+                    self.line_join,
+                )
+            }
+            _ => {
+                let text = Parser::get_string_val(inner_pair.clone());
+                // Skip the opening quote.
+                let start = inner_pair.as_span().start() + 1;
+                let positions = (start..=start + text.len()).collect();
+                (text, positions)
+            }
+        }
+    }
+
+    fn check_markup(
+        text: &str,
+        positions: &[usize],
+        pair: Pair<Rule>,
+    ) -> Result<(), pest::error::Error<Rule>> {
+        markup::parse(text).map(|_| ()).map_err(|e| {
+            let position = Position::new(pair.as_span().get_input(), positions[e.offset]).unwrap();
+            pest::error::Error::new_from_pos(
+                ErrorVariant::CustomError { message: e.message },
+                position,
+            )
+        })
+    }
+
+    /// Checks that `Character.variable` refers to a variable declared by the character.
+    fn check_character_variable(&self, pair: Pair<Rule>) -> Result<(), pest::error::Error<Rule>> {
+        let (character_id, variable_name) = match pair.as_str().split_once('.') {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        match self.characters.iter().find(|c| c.id() == character_id) {
+            Some(character) if !character.has_variable(variable_name) => {
+                Err(pest::error::Error::new_from_span(
+                    ErrorVariant::CustomError {
+                        message: format!(
+                            "Character '{character_id}' has no variable '{variable_name}'."
+                        ),
+                    },
+                    pair.as_span(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_expression(pair: Pair<Rule>) -> String {
+        pair.into_inner()
+            .map(|part| part.as_str())
+            .collect::<Vec<&str>>()
+            .join("+")
+    }
+
+    /// Checks that each part of an expression names one of the character's portraits, outfits or
+    /// expressions. Characters without any portraits accept every expression.
+    fn check_expression(
+        character: &Character,
+        pair: Pair<Rule>,
+    ) -> Result<(), pest::error::Error<Rule>> {
+        let names = character.portrait_names();
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        for part in pair.into_inner() {
+            let name = part.as_str();
+            if names.contains(&name) {
+                continue;
+            }
+            let suggestion = match closest_match(name, &names) {
+                Some(closest) => format!(" Did you mean '{closest}'?"),
+                None => String::new(),
+            };
+            return Err(pest::error::Error::new_from_span(
+                ErrorVariant::CustomError {
+                    message: format!(
+                        "Character '{}' has no portrait '{name}'.{suggestion}",
+                        character.id()
+                    ),
+                },
+                part.as_span(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Finds the character an ident refers to by ID or alias, along with the name to display.
+    fn find_character(
+        &self,
+        pair: Pair<Rule>,
+    ) -> Result<(&Character, String), pest::error::Error<Rule>> {
+        let id = pair.as_str();
+        if let Some(c) = self.characters.iter().find(|c| *c.id() == id) {
+            return Ok((c, c.display_name().to_owned()));
+        }
+        match self
+            .characters
+            .iter()
+            .find_map(|c| c.get_alias_name(id).map(|name| (c, name)))
+        {
+            Some(found) => Ok(found),
+            None => Err(pest::error::Error::new_from_span(
+                ErrorVariant::CustomError {
+                    message: format!("Character '{id}' not found."),
+                },
+                pair.as_span(),
+            )),
+        }
+    }
+
+    fn get_line_id(pair: Pair<Rule>) -> String {
+        pair.into_inner().next().unwrap().as_str().to_owned()
+    }
+
+    pub fn dialogue_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut statements = Vec::new();
         let mut choices = Vec::new();
         let mut character_id = None;
@@ -127,37 +520,57 @@ impl Parser {
         let mut text = String::new();
         let mut character: Option<&Character> = None;
         let mut expression: Option<String> = None;
+        let mut line_id = None;
+        let mut voice = None;
+        let mut has_fallback = false;
 
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
-                Rule::speaker => speaker = Some(Parser::get_string_val(inner_pair)),
-                Rule::text => text = Parser::get_string_val(inner_pair),
-                Rule::alias => speaker = Some(Parser::get_string_val(inner_pair)),
-                Rule::expression => expression = Some(inner_pair.as_str().to_owned()),
+                Rule::speaker => {
+                    speaker = Some(template::unescape(&Parser::get_string_val(inner_pair)))
+                }
+                Rule::text => {
+                    let positions;
+                    (text, positions) = self.get_text(inner_pair.clone());
+                    Parser::check_markup(&text, &positions, inner_pair)?;
+                }
+                Rule::alias => {
+                    speaker = Some(template::unescape(&Parser::get_string_val(inner_pair)))
+                }
+                Rule::expression => {
+                    if let Some(character) = character {
+                        Parser::check_expression(character, inner_pair.clone())?;
+                    }
+                    expression = Some(Parser::get_expression(inner_pair))
+                }
+                Rule::voice => {
+                    voice = Some(Parser::get_string_val(
+                        inner_pair.into_inner().next().unwrap(),
+                    ))
+                }
+                Rule::line_tag => line_id = Some(Parser::get_line_id(inner_pair)),
                 // Rule::portrait => portrait_path = Some(character.as_ref().unwrap().get_portrait_path(inner_pair.as_str()).unwrap().to_owned()),
                 Rule::ident => {
-                    let id = inner_pair.as_str();
-                    let c = self.characters.iter().find(|c| *c.id() == id);
-                    match c {
-                        Some(c) => {
-                            character = Some(c);
-                            character_id = Some(id.to_owned());
-                            speaker = Some(c.display_name().to_owned());
-                        }
-                        None => {
-                            let c = self
-                                .characters
-                                .iter()
-                                .find(|c| c.get_alias_name(id).is_some())
-                                .unwrap_or_else(|| panic!("Character '{id}' not found."));
-                            character = Some(c);
-                            character_id = Some(c.id().to_owned());
-                            speaker = Some(c.get_alias_name(id).unwrap().to_owned());
-                        }
-                    }
+                    let (c, name) = self.find_character(inner_pair)?;
+                    character = Some(c);
+                    character_id = Some(c.id().to_owned());
+                    speaker = Some(name);
                 }
                 Rule::choice => {
-                    choices.append(&mut self.choice_pair(inner_pair));
+                    let is_fallback = inner_pair
+                        .clone()
+                        .into_inner()
+                        .any(|p| p.as_rule() == Rule::fallback);
+                    if is_fallback && has_fallback {
+                        return Err(pest::error::Error::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: "A line can only have one fallback choice.".to_owned(),
+                            },
+                            inner_pair.as_span(),
+                        ));
+                    }
+                    has_fallback |= is_fallback;
+                    choices.append(&mut self.choice_pair(inner_pair, state)?);
                 }
                 _ => (),
             }
@@ -168,40 +581,61 @@ impl Parser {
             speaker,
             text,
             expression,
-            portraits: if let Some(character) = character {
-                character.portraits().to_owned()
-            } else {
-                HashMap::new()
-            },
+            line_id,
+            voice,
         });
         statements.append(&mut choices);
         statements.push(Stmt::EndDialogue);
-        statements
+        Ok(statements)
     }
 
-    pub fn choice_pair(&self, pair: Pair<Rule>) -> Timeline {
+    pub fn choice_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut statements = Vec::new();
         let mut children = Vec::new();
         let mut text = String::new();
         let mut condition = None;
+        let mut line_id = None;
+        let mut once = false;
+        let mut fallback = false;
 
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
-                Rule::text => text = Parser::get_string_val(inner_pair),
+                Rule::text => {
+                    let positions;
+                    (text, positions) = self.get_text(inner_pair.clone());
+                    Parser::check_markup(&text, &positions, inner_pair)?;
+                }
                 Rule::bool_expr => condition = Some(inner_pair.as_str().to_owned()),
-                _ => children.append(&mut self.events_pair(inner_pair)),
+                Rule::line_tag => line_id = Some(Parser::get_line_id(inner_pair)),
+                Rule::once => once = true,
+                Rule::fallback => fallback = true,
+                _ => children.append(&mut self.events_pair(inner_pair, state)?),
             }
         }
 
-        statements.push(Stmt::Choice { text, condition });
+        statements.push(Stmt::Choice {
+            text,
+            condition,
+            line_id,
+            once,
+            fallback,
+        });
 
         statements.append(&mut children);
         statements.push(Stmt::EndChoice);
 
-        statements
+        Ok(statements)
     }
 
-    pub fn if_pair(&self, pair: Pair<Rule>) -> Timeline {
+    pub fn if_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut statements = Vec::new();
         let mut children = Vec::new();
         let mut condition = String::new();
@@ -209,7 +643,7 @@ impl Parser {
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::bool_expr => condition = inner_pair.as_str().to_owned(),
-                _ => children.append(&mut self.events_pair(inner_pair)),
+                _ => children.append(&mut self.events_pair(inner_pair, state)?),
             }
         }
 
@@ -218,55 +652,371 @@ impl Parser {
         statements.append(&mut children);
         statements.push(Stmt::EndIf);
 
-        statements
+        Ok(statements)
+    }
+
+    /// Parses `while condition:` and `loop:` blocks.
+    pub fn loop_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut statements = Vec::new();
+        let mut children = Vec::new();
+        let mut condition = None;
+
+        state.loop_depth += 1;
+        for inner_pair in pair.into_inner() {
+            let result = match inner_pair.as_rule() {
+                Rule::bool_expr => {
+                    condition = Some(inner_pair.as_str().to_owned());
+                    Ok(vec![])
+                }
+                _ => self.events_pair(inner_pair, state),
+            };
+            match result {
+                Ok(mut timeline) => children.append(&mut timeline),
+                Err(e) => {
+                    state.loop_depth -= 1;
+                    return Err(e);
+                }
+            }
+        }
+        state.loop_depth -= 1;
+
+        statements.push(Stmt::Loop { condition });
+        statements.append(&mut children);
+        statements.push(Stmt::EndLoop);
+
+        Ok(statements)
+    }
+
+    pub fn match_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut statements = Vec::new();
+        let mut arms = Vec::new();
+        let mut expression = String::new();
+        // Cases are literals, so duplicates are found by comparing their values.
+        let mut values: Vec<Value> = Vec::new();
+        let mut has_default = false;
+
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::match_keyword => continue,
+                Rule::expr => {
+                    expression = inner_pair.as_str().to_owned();
+                    continue;
+                }
+                _ => (),
+            }
+
+            let span = inner_pair.as_span();
+            let mut value = None;
+            let mut children = Vec::new();
+            for arm_pair in inner_pair.into_inner() {
+                match arm_pair.as_rule() {
+                    Rule::literal => value = Some(arm_pair),
+                    _ => children.append(&mut self.events_pair(arm_pair, state)?),
+                }
+            }
+
+            match &value {
+                Some(value) => {
+                    let parsed = evalexpr::eval(value.as_str()).map_err(|e| {
+                        pest::error::Error::new_from_span(
+                            ErrorVariant::CustomError {
+                                message: format!("Invalid case '{}': {e}", value.as_str()),
+                            },
+                            value.as_span(),
+                        )
+                    })?;
+                    if values.contains(&parsed) {
+                        state.warn(
+                            format!(
+                                "Duplicate case '{}', only the first one can match.",
+                                value.as_str()
+                            ),
+                            value.as_span(),
+                        );
+                    }
+                    values.push(parsed);
+                }
+                None if has_default => {
+                    return Err(pest::error::Error::new_from_span(
+                        ErrorVariant::CustomError {
+                            message: "A match can only have one default.".to_owned(),
+                        },
+                        span,
+                    ))
+                }
+                None => has_default = true,
+            }
+
+            arms.push(Stmt::Case {
+                value: value.map(|value| value.as_str().to_owned()),
+            });
+            arms.append(&mut children);
+            arms.push(Stmt::EndCase);
+        }
+
+        statements.push(Stmt::Match { expression });
+        statements.append(&mut arms);
+        statements.push(Stmt::EndMatch);
+
+        Ok(statements)
+    }
+
+    /// Parses `sequence:`, `cycle:`, `shuffle:` and `once:` blocks, whose statements are the
+    /// alternatives.
+    pub fn alternatives_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut statements = Vec::new();
+        let mut children = Vec::new();
+        let mut alternation = Alternation::Sequence;
+
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::alternation => {
+                    alternation = match inner_pair.as_str() {
+                        "cycle" => Alternation::Cycle,
+                        "shuffle" => Alternation::Shuffle,
+                        "once" => Alternation::Once,
+                        _ => Alternation::Sequence,
+                    }
+                }
+                _ => {
+                    children.append(&mut self.events_pair(inner_pair, state)?);
+                    children.push(Stmt::EndAlternative);
+                }
+            }
+        }
+
+        statements.push(Stmt::Alternatives { alternation });
+        statements.append(&mut children);
+        statements.push(Stmt::EndAlternatives);
+
+        Ok(statements)
+    }
+
+    pub fn break_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
+        if state.loop_depth == 0 {
+            return Err(pest::error::Error::new_from_span(
+                ErrorVariant::CustomError {
+                    message: "'break' outside of a loop.".to_owned(),
+                },
+                pair.as_span(),
+            ));
+        }
+        Ok(vec![Stmt::Break])
     }
 
-    pub fn call_pair(&self, pair: Pair<Rule>) -> Timeline {
+    pub fn call_pair(&self, pair: Pair<Rule>) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut jump = false;
         let mut timeline_name = String::new();
+        let mut arguments: Vec<(String, String)> = Vec::new();
 
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::jump => jump = true,
                 Rule::string => timeline_name = Parser::get_string_val(inner_pair),
+                Rule::arguments => {
+                    for argument in inner_pair.into_inner() {
+                        let mut parts = argument.into_inner();
+                        let name = parts.next().unwrap();
+                        if arguments.iter().any(|(n, _)| n == name.as_str()) {
+                            return Err(pest::error::Error::new_from_span(
+                                ErrorVariant::CustomError {
+                                    message: format!("Duplicate argument '{}'.", name.as_str()),
+                                },
+                                name.as_span(),
+                            ));
+                        }
+                        let expression = parts.next().unwrap().as_str().to_owned();
+                        arguments.push((name.as_str().to_owned(), expression));
+                    }
+                }
                 _ => (),
             }
         }
 
-        vec![Stmt::Call {
+        Ok(vec![Stmt::Call {
             jump,
             timeline_name,
-        }]
+            arguments,
+        }])
     }
 
-    pub fn set_pair(&self, pair: Pair<Rule>) -> Timeline {
+    pub fn set_pair(&self, pair: Pair<Rule>) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut variable_name = String::new();
         let mut expression = String::new();
 
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
-                Rule::ident => variable_name = inner_pair.as_str().to_owned(),
+                Rule::variable => {
+                    variable_name = inner_pair.as_str().to_owned();
+                    self.check_character_variable(inner_pair)?;
+                }
                 Rule::expr => expression = inner_pair.as_str().to_owned(),
                 _ => (),
             }
         }
 
-        vec![Stmt::Set {
+        Ok(vec![Stmt::Set {
             variable_name,
             expression,
-        }]
+        }])
+    }
+
+    pub fn show_pair(&self, pair: Pair<Rule>) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut character = None;
+        let mut expression = None;
+        let mut position = None;
+
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ident => character = Some(self.find_character(inner_pair)?.0),
+                Rule::expression => {
+                    if let Some(character) = character {
+                        Parser::check_expression(character, inner_pair.clone())?;
+                    }
+                    expression = Some(Parser::get_expression(inner_pair))
+                }
+                Rule::position => position = Some(inner_pair.as_str().to_owned()),
+                _ => (),
+            }
+        }
+
+        Ok(vec![Stmt::Show {
+            character_id: character.map(|c| c.id().to_owned()).unwrap_or_default(),
+            expression,
+            position,
+        }])
+    }
+
+    pub fn hide_pair(&self, pair: Pair<Rule>) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut character_id = String::new();
+
+        for inner_pair in pair.into_inner() {
+            if inner_pair.as_rule() == Rule::ident {
+                character_id = self.find_character(inner_pair)?.0.id().to_owned()
+            }
+        }
+
+        Ok(vec![Stmt::Hide { character_id }])
+    }
+
+    pub fn move_pair(&self, pair: Pair<Rule>) -> Result<Timeline, pest::error::Error<Rule>> {
+        let mut character_id = String::new();
+        let mut position = String::new();
+
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ident => character_id = self.find_character(inner_pair)?.0.id().to_owned(),
+                Rule::position => position = inner_pair.as_str().to_owned(),
+                _ => (),
+            }
+        }
+
+        Ok(vec![Stmt::Move {
+            character_id,
+            position,
+        }])
+    }
+
+    /// Parses the included file in place of the `include` statement.
+    pub fn include_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
+        let string = pair.into_inner().next().unwrap();
+        let span = string.as_span();
+        let error = |message: String| {
+            pest::error::Error::new_from_span(ErrorVariant::CustomError { message }, span)
+        };
+        let name = Parser::get_string_val(string);
+
+        let script_dir = match state.script_dir.as_ref().or(self.script_dir.as_ref()) {
+            Some(script_dir) => script_dir,
+            None => {
+                return Err(error(format!(
+                    "Can't include '{name}' without a script directory."
+                )))
+            }
+        };
+        let path = Path::new(&script_dir)
+            .join(format!("{}.{FILE_EXTENSION}", name.replace('.', "/")))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        if !Path::new(&path).is_file() {
+            return Err(error(format!("Included file '{path}' not found.")));
+        }
+
+        let key = include_key(&path);
+        if let Some(start) = state.include_stack.iter().position(|(_, k)| *k == key) {
+            let cycle = state.include_stack[start..]
+                .iter()
+                .map(|(filename, _)| filename.as_str())
+                .chain([path.as_str()])
+                .collect::<Vec<&str>>()
+                .join(" -> ");
+            return Err(error(format!("Include cycle: {cycle}.")));
+        }
+
+        // Errors keep their place in the included file, and say where it was included from.
+        let line = span.start_pos().line_col().0;
+        let site = match state.include_stack.last() {
+            Some((filename, _)) => format!("{filename}:{line}"),
+            None => format!("line {line}"),
+        };
+        self.parse_file_with(&path, state).map_err(|mut e| {
+            e.variant = ErrorVariant::CustomError {
+                message: format!("{}\nIncluded from {site}.", e.variant.message()),
+            };
+            e
+        })
     }
 
-    pub fn events_pair(&self, pair: Pair<Rule>) -> Timeline {
+    pub fn events_pair(
+        &self,
+        pair: Pair<Rule>,
+        state: &mut ParseState,
+    ) -> Result<Timeline, pest::error::Error<Rule>> {
         let mut statements = Vec::new();
         match pair.as_rule() {
-            Rule::dialogue => statements = self.dialogue_pair(pair),
-            Rule::if_stmt => statements = self.if_pair(pair),
-            Rule::call => statements = self.call_pair(pair),
-            Rule::set => statements = self.set_pair(pair),
+            Rule::dialogue => statements = self.dialogue_pair(pair, state)?,
+            Rule::if_stmt => statements = self.if_pair(pair, state)?,
+            Rule::call => statements = self.call_pair(pair)?,
+            Rule::set => statements = self.set_pair(pair)?,
+            Rule::show => statements = self.show_pair(pair)?,
+            Rule::hide => statements = self.hide_pair(pair)?,
+            Rule::move_stmt => statements = self.move_pair(pair)?,
+            Rule::include => statements = self.include_pair(pair, state)?,
+            Rule::while_stmt | Rule::loop_stmt => statements = self.loop_pair(pair, state)?,
+            Rule::break_stmt => statements = self.break_pair(pair, state)?,
+            Rule::alternatives => statements = self.alternatives_pair(pair, state)?,
+            Rule::match_stmt => statements = self.match_pair(pair, state)?,
+            Rule::return_stmt => statements = vec![Stmt::Return],
+            Rule::end => statements = vec![Stmt::End],
+            Rule::label => {
+                statements = vec![Stmt::Label {
+                    name: pair.into_inner().as_str().to_owned(),
+                }]
+            }
             _ => (),
         }
 
-        statements
+        Ok(statements)
     }
 }
@@ -0,0 +1,130 @@
+//! Pluralization and grammatical gender for templates.
+//!
+//! `plural(n, "coin", "coins")` picks a form using the CLDR cardinal plural rules of the active
+//! locale. The forms are given in CLDR order (zero, one, two, few, many, other), listing only the
+//! categories the locale uses, e.g. one/few/many/other for Russian. Missing forms fall back to
+//! the last one. Locales without CLDR plural rules are an error.
+//!
+//! `pronoun(gender, "he", "she", "they")` picks a form by gender. `gender` is either an index or
+//! a string starting with `m` (first form) or `f` (second form). Anything else picks the last
+//! form.
+
+use evalexpr::{EvalexprError, EvalexprResult, Value};
+use intl_pluralrules::{PluralRuleType, PluralRules};
+use unic_langid::LanguageIdentifier;
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PluralCategory {
+    Zero,
+    One,
+    Two,
+    Few,
+    Many,
+    Other,
+}
+
+/// Numbers that between them fall in every category any locale uses.
+const SAMPLES: [f64; 11] = [0.1, 0.5, 1.1, 1.5, 2.1, 2.5, 3.5, 5.5, 10.5, 11.5, 1e6];
+
+/// The CLDR rules of `locale`, or of its language if there are none for the region.
+fn rules(locale: &str) -> Result<PluralRules, String> {
+    let unsupported = || format!("Locale '{locale}' has no plural rules.");
+    let langid: LanguageIdentifier = locale.parse().map_err(|_| unsupported())?;
+    PluralRules::create(langid.to_owned(), PluralRuleType::CARDINAL)
+        .or_else(|_| {
+            PluralRules::create(
+                LanguageIdentifier::from_parts(langid.language, None, None, &[]),
+                PluralRuleType::CARDINAL,
+            )
+        })
+        .map_err(|_| unsupported())
+}
+
+fn select(rules: &PluralRules, n: f64) -> PluralCategory {
+    match rules.select(n) {
+        Ok(intl_pluralrules::PluralCategory::ZERO) => PluralCategory::Zero,
+        Ok(intl_pluralrules::PluralCategory::ONE) => PluralCategory::One,
+        Ok(intl_pluralrules::PluralCategory::TWO) => PluralCategory::Two,
+        Ok(intl_pluralrules::PluralCategory::FEW) => PluralCategory::Few,
+        Ok(intl_pluralrules::PluralCategory::MANY) => PluralCategory::Many,
+        _ => PluralCategory::Other,
+    }
+}
+
+/// Checks that `locale` has plural rules.
+pub fn check_locale(locale: &str) -> Result<(), String> {
+    rules(locale).map(|_| ())
+}
+
+/// The plural categories used by `locale`, in CLDR order.
+pub fn plural_categories(locale: &str) -> Result<Vec<PluralCategory>, String> {
+    let rules = rules(locale)?;
+    let mut categories: Vec<PluralCategory> = (0..=200)
+        .map(f64::from)
+        .chain(SAMPLES)
+        .map(|n| select(&rules, n))
+        .collect();
+    categories.sort_unstable();
+    categories.dedup();
+    Ok(categories)
+}
+
+/// The CLDR cardinal plural category of `n` in `locale`.
+pub fn plural_category(locale: &str, n: f64) -> Result<PluralCategory, String> {
+    Ok(select(&rules(locale)?, n))
+}
+
+fn arguments(argument: &Value, function: &str) -> EvalexprResult<(Value, Vec<String>)> {
+    let arguments = argument.as_tuple()?;
+    if arguments.len() < 2 {
+        return Err(EvalexprError::CustomMessage(format!(
+            "{function}() expects a value and at least one form."
+        )));
+    }
+    let forms = arguments[1..]
+        .iter()
+        .map(|form| form.as_string())
+        .collect::<EvalexprResult<Vec<String>>>()?;
+    Ok((arguments[0].to_owned(), forms))
+}
+
+/// `plural(n, forms...)`.
+pub fn plural(locale: &str, argument: &Value) -> EvalexprResult<Value> {
+    let (n, forms) = arguments(argument, "plural")?;
+    let category = plural_category(locale, n.as_number()?).map_err(EvalexprError::CustomMessage)?;
+    let index = plural_categories(locale)
+        .map_err(EvalexprError::CustomMessage)?
+        .iter()
+        .position(|c| *c == category)
+        .unwrap_or(0);
+
+    Ok(Value::String(
+        forms
+            .get(index)
+            .unwrap_or_else(|| forms.last().unwrap())
+            .to_owned(),
+    ))
+}
+
+/// `pronoun(gender, forms...)`.
+pub fn pronoun(argument: &Value) -> EvalexprResult<Value> {
+    let (gender, forms) = arguments(argument, "pronoun")?;
+    let index = match &gender {
+        Value::Int(i) => usize::try_from(*i).unwrap_or(usize::MAX),
+        Value::String(gender) => match gender.to_lowercase().chars().next() {
+            Some('m') => 0,
+            Some('f') => 1,
+            _ => usize::MAX,
+        },
+        _ => usize::MAX,
+    };
+
+    Ok(Value::String(
+        forms
+            .get(index)
+            .unwrap_or_else(|| forms.last().unwrap())
+            .to_owned(),
+    ))
+}
@@ -0,0 +1,80 @@
+//! The random number generator of the language, a SplitMix64 whose state is saved with the story
+//! so that replaying from a save gives the same results.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use evalexpr::{EvalexprError, EvalexprResult, Value};
+
+/// The state is atomic because expressions draw numbers through a shared reference.
+#[derive(Debug)]
+pub(crate) struct Rng {
+    state: AtomicU64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    /// Seeded from the clock.
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default();
+        Rng::new(nanos)
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state.load(Ordering::Relaxed)
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be 0.
+    pub fn below(&self, n: u64) -> u64 {
+        // Multiply and shift instead of `%`, which favours small numbers.
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// A number in `0.0..1.0`.
+    pub fn next_f64(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// `random(min, max)`: an integer from `min` to `max`, both included.
+pub fn random(rng: &Rng, argument: &Value) -> EvalexprResult<Value> {
+    let arguments = argument.as_fixed_len_tuple(2)?;
+    let (min, max) = (arguments[0].as_int()?, arguments[1].as_int()?);
+    if min > max {
+        return Err(EvalexprError::CustomMessage(format!(
+            "random() expects min <= max, got {min} and {max}."
+        )));
+    }
+    let range = max.abs_diff(min).wrapping_add(1);
+    let offset = match range {
+        // The whole range of i64.
+        0 => rng.next_u64(),
+        _ => rng.below(range),
+    };
+    Ok(Value::Int(min.wrapping_add(offset as i64)))
+}
+
+/// `chance(p)`: true with probability `p`, from 0 to 1.
+pub fn chance(rng: &Rng, argument: &Value) -> EvalexprResult<Value> {
+    Ok(Value::Boolean(rng.next_f64() < argument.as_number()?))
+}
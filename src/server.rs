@@ -1,16 +1,25 @@
-use std::{collections::HashMap, vec};
-
-use evalexpr::{eval_boolean_with_context, eval_with_context, HashMapContext, Value};
-
-use nom::{
-    self,
-    bytes::complete::{tag, take_until},
-    multi::many0,
-    sequence::{delimited, preceded},
-    IResult,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    vec,
 };
 
-use crate::parser::Stmt;
+use evalexpr::{eval_with_context, Context, HashMapContext, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bytecode::{Op, Program, TimelineId},
+    character::{CharacterRegistry, Characters},
+    context::ScriptContext,
+    format::{self, Formatter},
+    markup::{self, Span},
+    parser::Stmt,
+    plural::{self, DEFAULT_LOCALE},
+    random::Rng,
+    template::{self, Alternation, Piece},
+    voice::resolve_voice_path,
+    Character,
+};
 
 pub type Timeline = Vec<Stmt>;
 pub type Timelines = HashMap<String, Timeline>;
@@ -34,53 +43,177 @@ impl<T> Stack<T> for Vec<T> {
         self[last_index] = new_val
     }
 }
+
+pub struct Choice {
+    /// `text` without markup.
+    pub text: String,
+    pub spans: Vec<Span>,
+    pub hidden: bool,
+    pub line_id: Option<String>,
+}
+
 pub enum Event {
     Dialogue {
         character_id: Option<String>,
         speaker: Option<String>,
+        /// `text` without markup.
         text: String,
-        choices: Vec<(String, bool)>,
-        portrait_path: Option<String>,
+        spans: Vec<Span>,
+        choices: Vec<Choice>,
+        /// Paths of the speaker's portrait layers, from bottom to top.
+        portrait_layers: Vec<String>,
+        line_id: Option<String>,
+        voice_path: Option<String>,
     },
     Set {
         variable_name: String,
         new_value: Value,
     },
+    SceneChanged(SceneChange),
+    /// The story reached an `end` statement.
+    End,
     Ignore,
 }
 
+/// A character on stage.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagedCharacter {
+    pub character_id: String,
+    pub position: Option<String>,
+}
+
+/// The characters on stage, from back to front in the order they were shown.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SceneState {
+    pub characters: Vec<StagedCharacter>,
+}
+
+impl SceneState {
+    pub fn get(&self, character_id: &str) -> Option<&StagedCharacter> {
+        self.characters
+            .iter()
+            .find(|c| c.character_id == character_id)
+    }
+
+    fn get_mut(&mut self, character_id: &str) -> Option<&mut StagedCharacter> {
+        self.characters
+            .iter_mut()
+            .find(|c| c.character_id == character_id)
+    }
+}
+
+/// A change to the [`SceneState`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneChange {
+    /// The character entered the stage, or changed position or expression while on it.
+    Shown {
+        character_id: String,
+        position: Option<String>,
+        /// Paths of the character's portrait layers, from bottom to top.
+        portrait_layers: Vec<String>,
+    },
+    Hidden {
+        character_id: String,
+    },
+    Moved {
+        character_id: String,
+        position: String,
+    },
+}
+
+/// Everything needed to resume a story later.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub timeline_stack: Vec<String>,
+    pub index_stack: Vec<usize>,
+    pub choice_indexes: Option<Vec<usize>>,
+    pub character_expressions: HashMap<String, String>,
+    #[serde(default)]
+    pub character_outfits: HashMap<String, String>,
+    /// The character variables, e.g. `Elira.affection`.
+    pub variables: HashMap<String, Value>,
+    #[serde(default)]
+    pub scene: SceneState,
+    /// The IDs of the choices chosen so far.
+    #[serde(default)]
+    pub chosen_choices: HashSet<String>,
+    /// The locals of each timeline in `timeline_stack`.
+    #[serde(default)]
+    pub locals_stack: Vec<HashMap<String, Value>>,
+    /// How many times each timeline and label (as `timeline#label`) was entered.
+    #[serde(default)]
+    pub visits: HashMap<String, u64>,
+    /// How many times each set of alternatives was reached.
+    #[serde(default)]
+    pub alternative_visits: HashMap<String, usize>,
+    #[serde(default)]
+    pub random_state: u64,
+}
+
+/// A running timeline, the position of its next instruction and its locals.
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    timeline: TimelineId,
+    pc: usize,
+    locals: HashMap<String, Value>,
+}
+
 pub struct Server {
-    timelines: Timelines,
-    timeline_stack: Vec<String>,
-    index_stack: Vec<usize>,
+    program: Program,
+    frames: Vec<Frame>,
     choice_indexes: Option<Vec<usize>>,
+    chosen_choices: HashSet<String>,
+    visits: HashMap<String, u64>,
+    alternative_visits: Mutex<HashMap<String, usize>>,
+    rng: Rng,
     context: HashMapContext,
     character_expressions: HashMap<String, String>,
+    character_outfits: HashMap<String, String>,
+    voice_pattern: Option<String>,
+    strict_templates: bool,
+    formatters: HashMap<String, Formatter>,
+    locale: String,
+    characters: CharacterRegistry,
+    variables: HashMap<String, Value>,
+    scene: SceneState,
 }
 
 impl Server {
     pub fn new(timelines: Timelines, context: HashMapContext) -> Self {
         Server {
-            timelines,
+            program: Program::compile(timelines),
             context,
-            timeline_stack: vec![],
-            index_stack: vec![],
+            frames: vec![],
             choice_indexes: None,
+            chosen_choices: HashSet::new(),
+            visits: HashMap::new(),
+            alternative_visits: Mutex::new(HashMap::new()),
+            rng: Rng::from_time(),
             character_expressions: HashMap::new(),
+            character_outfits: HashMap::new(),
+            voice_pattern: None,
+            strict_templates: false,
+            formatters: HashMap::new(),
+            locale: DEFAULT_LOCALE.to_owned(),
+            characters: CharacterRegistry::default(),
+            variables: HashMap::new(),
+            scene: SceneState::default(),
         }
     }
 
     pub fn check_timeline_exists(&self, timeline_name: &str) -> Result<(), String> {
-        if self.timelines.contains_key(timeline_name) {
-            Ok(())
-        } else {
-            Err(format!("Timeline '{timeline_name}' not found."))
-        }
+        self.timeline_id(timeline_name).map(|_| ())
+    }
+
+    fn timeline_id(&self, timeline_name: &str) -> Result<TimelineId, String> {
+        self.program
+            .id(timeline_name)
+            .ok_or_else(|| format!("Timeline '{timeline_name}' not found."))
     }
 
     pub fn check_index_valid(&self, timeline_name: &str, index: usize) -> Result<(), String> {
-        self.check_timeline_exists(timeline_name)?;
-        if self.timelines.get(timeline_name).unwrap().len() > index {
+        let id = self.timeline_id(timeline_name)?;
+        if self.program.code(id).unwrap().len() > index {
             Ok(())
         } else {
             Err(format!(
@@ -98,8 +231,15 @@ impl Server {
                 .unwrap();
         }
 
-        self.timeline_stack = timeline_stack;
-        self.index_stack = index_stack;
+        self.frames = timeline_stack
+            .iter()
+            .zip(index_stack)
+            .map(|(timeline_name, pc)| Frame {
+                timeline: self.program.id(timeline_name).unwrap(),
+                pc,
+                locals: HashMap::new(),
+            })
+            .collect();
     }
 
     pub fn set_character_expressions(&mut self, character_expressions: HashMap<String, String>) {
@@ -107,300 +247,648 @@ impl Server {
         self.character_expressions = character_expressions
     }
 
+    pub fn set_character_outfits(&mut self, character_outfits: HashMap<String, String>) {
+        self.character_outfits = character_outfits
+    }
+
     pub fn choose(&mut self, choice: usize) {
-        self.index_stack.set_top(
-            *self
-                .choice_indexes
-                .as_ref()
-                .expect("No choices.")
-                .get(choice)
-                .expect("Invalid choice index"),
-        );
+        let choice_index = *self
+            .choice_indexes
+            .as_ref()
+            .expect("No choices.")
+            .get(choice)
+            .expect("Invalid choice index");
+        let frame = self.frames.last_mut().expect("No choices.");
+        frame.pc = choice_index;
+        let timeline = frame.timeline;
+        self.chosen_choices
+            .insert(self.site_id(timeline, choice_index));
+    }
+
+    /// Identifies a line, choice or alternatives block across saves: its line ID, or its
+    /// position.
+    fn site_id(&self, timeline: TimelineId, index: usize) -> String {
+        match self.program.code(timeline).map(|code| &code[index]) {
+            Some(Op::Line {
+                line_id: Some(line_id),
+                ..
+            })
+            | Some(Op::Choice {
+                line_id: Some(line_id),
+                ..
+            })
+            | Some(Op::Alternatives {
+                line_id: Some(line_id),
+                ..
+            }) => line_id.to_owned(),
+            _ => format!("{}:{index}", self.program.name(timeline)),
+        }
+    }
+
+    /// Whether a choice has been chosen, by line ID or as `timeline:position`.
+    pub fn was_chosen(&self, choice_id: &str) -> bool {
+        self.chosen_choices.contains(choice_id)
+    }
+
+    /// Sets the pattern used to find the voice file of lines without a `voice "file"` clause,
+    /// e.g. [`DEFAULT_VOICE_PATTERN`](crate::voice::DEFAULT_VOICE_PATTERN).
+    pub fn set_voice_pattern(&mut self, voice_pattern: Option<&str>) {
+        self.voice_pattern = voice_pattern.map(|v| v.to_owned())
+    }
+
+    /// Makes templates that fail to evaluate an error instead of leaving them in the text.
+    pub fn set_strict_templates(&mut self, strict_templates: bool) {
+        self.strict_templates = strict_templates
+    }
+
+    /// Replaces the templates in `text` and processes its escapes.
+    ///
+    /// Alternatives in `text` are counted with those of other calls for the same text.
+    pub fn render_text(&self, text: &str) -> Result<String, String> {
+        self.render(text, text, true, false)
+    }
+
+    /// Renders text shown at `site`, which the alternatives in it are counted by. Unless `count`
+    /// is set, they are picked without counting the visit, e.g. for hidden choices. With `markup`,
+    /// brackets in template values are escaped so that values can't change the markup.
+    fn render(&self, text: &str, site: &str, count: bool, markup: bool) -> Result<String, String> {
+        let pieces = template::pieces(text);
+        if self.strict_templates
+            && pieces
+                .iter()
+                .any(|piece| matches!(piece, Piece::Unbalanced(_)))
+        {
+            return Err(format!(
+                "Unbalanced brace in '{text}'. Use '{{{{' or '}}}}' for a literal brace."
+            ));
+        }
+
+        let mut output = String::with_capacity(text.len());
+        for (index, piece) in pieces.into_iter().enumerate() {
+            match piece {
+                Piece::Literal(literal) => output.push_str(&template::unescape(literal)),
+                Piece::Unbalanced(brace) => output.push_str(brace),
+                Piece::Alternatives(alternation, options) => {
+                    let site = format!("{site}:{index}");
+                    if let Some(option) =
+                        self.pick_alternative(alternation, site, options.len(), count)
+                    {
+                        output.push_str(&template::unescape(options[option]))
+                    }
+                }
+                Piece::Expression(template) => match self.render_template(template) {
+                    Ok(value) if markup => output.push_str(&value.replace('[', "[[")),
+                    Ok(value) => output.push_str(&value),
+                    Err(e) if self.strict_templates => return Err(e),
+                    Err(_) => output.push_str(&format!("{{{template}}}")),
+                },
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Renders the text of a line or choice, and splits it into plain text and markup spans.
+    fn render_markup(&self, text: &str, site: &str, count: bool) -> (String, Vec<Span>) {
+        let text = self
+            .render(text, site, count, true)
+            .unwrap_or_else(|e| panic!("{e}"));
+        // Markup was checked by the parser, but alternatives may still pick unbalanced tags.
+        let spans = markup::parse(&text).unwrap_or_else(|_| {
+            vec![Span::Text {
+                text: text.to_owned(),
+                styles: vec![],
+            }]
+        });
+        (markup::plain_text(&spans), spans)
+    }
+
+    fn pick_alternative(
+        &self,
+        alternation: Alternation,
+        site: String,
+        len: usize,
+        count: bool,
+    ) -> Option<usize> {
+        let mut alternative_visits = self.alternative_visits.lock().unwrap();
+        if !count {
+            let visits = alternative_visits.get(&site).copied().unwrap_or_default();
+            return alternation.pick(visits, len, |_| 0);
+        }
+        let visits = alternative_visits.entry(site).or_default();
+        let alternative = alternation.pick(*visits, len, |n| self.rng.below(n as u64) as usize);
+        *visits += 1;
+        alternative
+    }
+
+    /// Seeds the random number generator behind `random(min, max)`, `chance(p)` and shuffled
+    /// alternatives, e.g. to replay a story the same way.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed)
+    }
+
+    fn render_template(&self, template: &str) -> Result<String, String> {
+        let (expression, spec) = format::split_spec(template);
+        let value = eval_with_context(expression, &self.script_context())
+            .map_err(|e| format!("Error evaluating '{{{template}}}': {e}"))?;
+
+        match spec.and_then(|spec| self.formatters.get(spec)) {
+            Some(formatter) => formatter(&value),
+            None => format::format_value(&value, spec),
+        }
+        .map_err(|e| format!("Error formatting '{{{template}}}': {e}"))
+    }
+
+    /// Registers a formatter usable as `{value:name}` in templates.
+    pub fn register_formatter<F>(&mut self, name: &str, formatter: F)
+    where
+        F: Fn(&Value) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.formatters.insert(name.to_owned(), Box::new(formatter));
+    }
+
+    /// Sets the characters and initializes their variables that aren't set yet.
+    pub fn set_characters(&mut self, characters: Characters) {
+        self.set_character_registry(CharacterRegistry::new(characters));
+    }
+
+    /// Like [`Server::set_characters`], from a loaded [`CharacterRegistry`].
+    pub fn set_character_registry(&mut self, characters: CharacterRegistry) {
+        self.characters = characters;
+        self.init_character_variables();
+    }
+
+    /// Adds or replaces a character, e.g. to pick up new portraits without parsing the scripts
+    /// again.
+    pub fn update_character(&mut self, character: Character) {
+        self.characters.insert(character);
+        self.init_character_variables();
+    }
+
+    pub fn character_registry(&self) -> &CharacterRegistry {
+        &self.characters
+    }
+
+    fn init_character_variables(&mut self) {
+        for character in self.characters.characters() {
+            for (variable_name, value) in character.variables() {
+                self.variables.entry(variable_name).or_insert(value);
+            }
+        }
+    }
+
+    /// Looks up a character, e.g. the speaker of an [`Event::Dialogue`].
+    pub fn character(&self, character_id: &str) -> Option<&Character> {
+        self.characters.get(character_id)
     }
 
+    /// Sets the locale whose plural rules `plural(...)` follows, e.g. `"en"` or `"ru"`. Fails for
+    /// locales without CLDR plural rules.
+    pub fn set_locale(&mut self, locale: &str) -> Result<(), String> {
+        plural::check_locale(locale)?;
+        self.locale = locale.to_owned();
+        Ok(())
+    }
+
+    fn script_context(&self) -> ScriptContext<'_> {
+        ScriptContext {
+            locals: self.frames.peek().map(|frame| &frame.locals),
+            variables: &self.variables,
+            context: &self.context,
+            locale: &self.locale,
+            timeline: self
+                .frames
+                .peek()
+                .map(|frame| self.program.name(frame.timeline)),
+            visits: &self.visits,
+            program: &self.program,
+            rng: &self.rng,
+        }
+    }
+
+    /// Sets the host's variables and functions. The host is expected to apply
+    /// [`Event::Set`] to them, except for character variables, which the server keeps.
     pub fn set_context(&mut self, context: HashMapContext) {
         self.context = context
     }
 
+    /// A character variable, e.g. `Elira.affection`, or else a variable of the host.
+    pub fn variable(&self, variable_name: &str) -> Option<&Value> {
+        self.variables
+            .get(variable_name)
+            .or_else(|| self.context.get_value(variable_name))
+    }
+
+    /// Sets a character variable, e.g. `Elira.affection`.
+    pub fn set_variable(&mut self, variable_name: &str, value: Value) {
+        self.variables.insert(variable_name.to_owned(), value);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            timeline_stack: self
+                .frames
+                .iter()
+                .map(|frame| self.program.name(frame.timeline).to_owned())
+                .collect(),
+            index_stack: self.frames.iter().map(|frame| frame.pc).collect(),
+            locals_stack: self
+                .frames
+                .iter()
+                .map(|frame| frame.locals.to_owned())
+                .collect(),
+            choice_indexes: self.choice_indexes.to_owned(),
+            chosen_choices: self.chosen_choices.to_owned(),
+            character_expressions: self.character_expressions.to_owned(),
+            character_outfits: self.character_outfits.to_owned(),
+            variables: self.variables.to_owned(),
+            scene: self.scene.to_owned(),
+            visits: self.visits.to_owned(),
+            alternative_visits: self.alternative_visits.lock().unwrap().to_owned(),
+            random_state: self.rng.state(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.set_stack(snapshot.timeline_stack, snapshot.index_stack);
+        for (frame, locals) in self.frames.iter_mut().zip(snapshot.locals_stack) {
+            frame.locals = locals;
+        }
+        self.choice_indexes = snapshot.choice_indexes;
+        self.chosen_choices = snapshot.chosen_choices;
+        self.character_expressions = snapshot.character_expressions;
+        self.character_outfits = snapshot.character_outfits;
+        self.variables = snapshot.variables;
+        self.scene = snapshot.scene;
+        self.visits = snapshot.visits;
+        self.alternative_visits = Mutex::new(snapshot.alternative_visits);
+        self.rng = Rng::new(snapshot.random_state);
+        self.init_character_variables();
+    }
+
+    pub fn scene(&self) -> &SceneState {
+        &self.scene
+    }
+
+    /// Paths of the portrait layers for the current expression and outfit of a character.
+    pub fn portrait_layers(&self, character_id: &str) -> Vec<String> {
+        match self.character(character_id) {
+            Some(character) => character.portrait_layers(
+                self.character_expressions
+                    .get(character_id)
+                    .map(String::as_str),
+                self.character_outfits.get(character_id).map(String::as_str),
+            ),
+            None => vec![],
+        }
+    }
+
     pub fn start(&mut self, timeline_name: &str, index: usize) {
         self.check_index_valid(timeline_name, index).unwrap();
-        self.timeline_stack = vec![timeline_name.to_owned()];
-        self.index_stack = vec![index];
+        self.frames = vec![Frame {
+            timeline: self.program.id(timeline_name).unwrap(),
+            pc: index,
+            locals: HashMap::new(),
+        }];
         self.choice_indexes = None;
+        visit(&mut self.visits, timeline_name);
+    }
+
+    /// How many times a timeline, or a label by its full name (e.g. `tavern#bar`), was entered.
+    pub fn visits(&self, name: &str) -> u64 {
+        self.visits.get(name).copied().unwrap_or_default()
     }
 }
 
-fn templates(input: &str) -> IResult<&str, Vec<&str>> {
-    many0(preceded(
-        take_until("{"),
-        delimited(tag("{"), take_until("}"), tag("}")),
-    ))(input)
+fn visit(visits: &mut HashMap<String, u64>, name: &str) {
+    *visits.entry(name.to_owned()).or_default() += 1;
+}
+
+/// Each part of `smile+casual` sets an outfit or an expression.
+fn set_expression(
+    characters: &CharacterRegistry,
+    character_expressions: &mut HashMap<String, String>,
+    character_outfits: &mut HashMap<String, String>,
+    character_id: &str,
+    expression: &str,
+) {
+    let character = characters.get(character_id);
+    for part in expression.split('+') {
+        let layers = if character.is_some_and(|c| c.has_outfit(part)) {
+            &mut *character_outfits
+        } else {
+            &mut *character_expressions
+        };
+        layers.insert(character_id.to_owned(), part.to_owned());
+    }
 }
 
 impl Iterator for Server {
     type Item = Event;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.timeline_stack.peek() {
-            Some(timeline_name) => {
-                let timeline = self
-                    .timelines
-                    .get(timeline_name)
-                    .unwrap_or_else(|| panic!("Timeline '{timeline_name}' not found."));
-                let mut new_timeline_name: Option<String> = None;
-                let mut jump: Option<bool> = None;
-
-                let index = *self.index_stack.peek().unwrap();
-                let curr = &timeline[index];
-                let event = match curr {
-                    Stmt::Dialogue {
-                        character_id,
-                        speaker,
-                        text,
-                        expression,
-                        portraits,
-                        // portrait_path,
-                    } => {
-                        let mut next_index = index + 1;
-                        let mut choices = Vec::new();
-                        let mut choice_indexes = Vec::new();
-                        let mut nested_count = 0;
-                        let templates = templates(text)
-                            .unwrap()
-                            .1
-                            .iter()
-                            .map(|v| (v, eval_with_context(v, &self.context)))
-                            .filter(|v| v.1.is_ok())
-                            .map(|v| (*v.0, v.1.unwrap()))
-                            .collect::<Vec<(&str, Value)>>();
-                        let mut text = text.to_owned();
-
-                        let expression = match expression {
-                            Some(expression) => Some(expression),
-                            None => {
-                                if !self
-                                    .character_expressions
-                                    .contains_key(character_id.as_ref().unwrap())
-                                {
-                                    self.character_expressions.insert(
-                                        character_id.as_ref().unwrap().to_owned(),
-                                        "default".to_owned(),
-                                    );
-                                }
-                                self.character_expressions
-                                    .get(character_id.as_ref().unwrap())
-                            }
-                        };
-
-                        let portrait_path = match expression {
-                            Some(expression) => {
-                                let portrait_path = portraits.get(expression);
-                                match portrait_path {
-                                    Some(portrait_path) => {
-                                        let expression = expression.to_owned();
-                                        self.character_expressions.insert(
-                                            character_id.as_ref().unwrap().to_owned(),
-                                            expression,
-                                        );
-                                        Some(portrait_path.to_owned())
-                                    }
-                                    None => None,
-                                }
-                            }
-                            None => None,
-                        };
-
-                        for (variable_name, value) in templates {
-                            let string_val = match value {
-                                Value::String(v) => v,
-                                Value::Float(v) => v.to_string(),
-                                Value::Int(v) => v.to_string(),
-                                Value::Boolean(v) => v.to_string(),
-                                _ => "".to_owned(),
-                            };
-                            let new_text =
-                                &text.replace(&format!("{{{variable_name}}}"), &string_val);
-                            text = new_text.to_owned();
-                        }
-
-                        loop {
-                            let next_event = &timeline[next_index];
-                            match next_event {
-                                Stmt::EndDialogue => {
-                                    if nested_count > 0 {
-                                        nested_count -= 1
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                Stmt::Dialogue { .. } => nested_count += 1,
-                                Stmt::Choice { .. } => {
-                                    if nested_count > 0 {
-                                        nested_count += 1
-                                    } else {
-                                        choices.push(next_event);
-                                        choice_indexes.push(next_index.to_owned())
-                                    }
-                                }
-                                Stmt::EndChoice => {
-                                    if nested_count > 0 {
-                                        nested_count -= 1
-                                    }
-                                }
-                                Stmt::If { .. } => nested_count += 1,
-                                Stmt::EndIf => nested_count -= 1,
-                                Stmt::Call { .. } | Stmt::Set { .. } => (),
-                            }
-                            next_index += 1;
+        let (timeline, pc) = self.frames.peek().map(|frame| (frame.timeline, frame.pc))?;
+        let code = self
+            .program
+            .code(timeline)
+            .unwrap_or_else(|| panic!("Timeline '{}' not found.", self.program.name(timeline)));
+        let mut next_pc = pc + 1;
+        let mut leave = false;
+        let mut call: Option<(TimelineId, bool, HashMap<String, Value>)> = None;
+
+        let event = match &code[pc] {
+            Op::Line {
+                character_id,
+                speaker,
+                text,
+                expression,
+                line_id,
+                voice,
+                choices,
+                end,
+            } => {
+                let (text, spans) = self.render_markup(text, &self.site_id(timeline, pc), true);
+
+                let portrait_layers = match character_id {
+                    Some(character_id) => {
+                        if let Some(expression) = expression {
+                            set_expression(
+                                &self.characters,
+                                &mut self.character_expressions,
+                                &mut self.character_outfits,
+                                character_id,
+                                expression,
+                            );
                         }
+                        self.portrait_layers(character_id)
+                    }
+                    None => vec![],
+                };
 
-                        self.choice_indexes = Some(choice_indexes);
-                        self.index_stack.set_top(index + 1);
-                        // self.index += 1;
-                        Some(Event::Dialogue {
-                            character_id: character_id.to_owned(),
-                            speaker: speaker.to_owned(),
+                let (fallback, choice_indexes): (Vec<usize>, Vec<usize>) = choices
+                    .iter()
+                    .partition(|c| matches!(code[**c], Op::Choice { fallback: true, .. }));
+                let mut choices: Vec<Choice> = choice_indexes
+                    .iter()
+                    .map(|choice_index| {
+                        if let Op::Choice {
                             text,
-                            portrait_path,
-                            choices: choices
-                                .into_iter()
-                                .map(|c| {
-                                    if let Stmt::Choice { text, condition } = c {
-                                        let hide = match condition {
-                                            Some(condition) => {
-                                                !eval_boolean_with_context(condition, &self.context)
-                                                    .unwrap_or_else(|_| {
-                                                        panic!("Error evaluating '{condition}'")
-                                                    })
-                                            }
-                                            None => false,
-                                        };
-                                        (text.to_owned(), hide)
-                                    } else {
-                                        unreachable!()
-                                    }
-                                })
-                                .collect(),
-                        })
-                    }
-                    Stmt::Choice { .. } | Stmt::EndDialogue | Stmt::EndIf => {
-                        // self.index += 1;
-                        self.index_stack.set_top(index + 1);
-                        Some(Event::Ignore)
-                    }
-                    Stmt::EndChoice => {
-                        let mut next_index = index + 1;
-                        let mut nested_count = 0;
-                        let mut next_event = &timeline[next_index];
-
-                        if matches!(next_event, Stmt::Choice { .. }) {
-                            loop {
-                                match next_event {
-                                    Stmt::EndDialogue => {
-                                        if nested_count > 0 {
-                                            nested_count -= 1
-                                        } else {
-                                            break;
-                                        }
-                                    }
-                                    Stmt::Dialogue { .. }
-                                    | Stmt::Choice { .. }
-                                    | Stmt::If { .. } => nested_count += 1,
-                                    Stmt::EndChoice | Stmt::EndIf => nested_count -= 1,
-                                    Stmt::Call { .. } | Stmt::Set { .. } => (),
-                                }
-                                next_index += 1;
-                                next_event = &timeline[next_index];
+                            condition,
+                            line_id,
+                            once,
+                            ..
+                        } = &code[*choice_index]
+                        {
+                            let hide = match condition {
+                                Some(condition) => !condition
+                                    .eval_boolean(&self.script_context())
+                                    .unwrap_or_else(|_| {
+                                        panic!("Error evaluating '{}'", condition.source)
+                                    }),
+                                None => false,
+                            } || *once
+                                && self
+                                    .chosen_choices
+                                    .contains(&self.site_id(timeline, *choice_index));
+                            let (text, spans) = self.render_markup(
+                                text,
+                                &self.site_id(timeline, *choice_index),
+                                !hide,
+                            );
+                            Choice {
+                                text,
+                                spans,
+                                hidden: hide,
+                                line_id: line_id.to_owned(),
                             }
-                        }
-                        self.index_stack.set_top(next_index);
-                        // self.index = next_index;
-                        Some(Event::Ignore)
-                    }
-                    Stmt::If { condition } => {
-                        let evaluation = eval_boolean_with_context(condition, &self.context)
-                            .unwrap_or_else(|_| panic!("Error evaluating '{condition}'"));
-
-                        if evaluation {
-                            // self.index += 1;
-                            self.index_stack.set_top(index + 1);
                         } else {
-                            let mut next_index = index + 1;
-                            let mut nested_count = 0;
-
-                            loop {
-                                let next = &timeline[next_index];
-                                match next {
-                                    Stmt::Dialogue { .. }
-                                    | Stmt::Choice { .. }
-                                    | Stmt::If { .. } => nested_count += 1,
-                                    Stmt::EndChoice | Stmt::EndDialogue => nested_count -= 1,
-                                    Stmt::EndIf => {
-                                        if nested_count > 0 {
-                                            nested_count -= 1
-                                        } else {
-                                            break;
-                                        }
-                                    }
-                                    Stmt::Call { .. } | Stmt::Set { .. } => (),
-                                }
-                                next_index += 1;
-                            }
-                            // self.index = next_index;
-                            self.index_stack.set_top(next_index);
+                            unreachable!()
                         }
-                        Some(Event::Ignore)
+                    })
+                    .collect();
+
+                match fallback.first() {
+                    Some(fallback) if choices.iter().all(|c| c.hidden) => {
+                        self.chosen_choices
+                            .insert(self.site_id(timeline, *fallback));
+                        self.choice_indexes = Some(vec![]);
+                        choices = vec![];
+                        next_pc = *fallback;
                     }
-                    Stmt::Call {
-                        jump: j,
-                        timeline_name,
-                    } => {
-                        jump = Some(*j);
-                        new_timeline_name = Some(timeline_name.to_owned());
-                        self.index_stack.set_top(index + 1);
-                        Some(Event::Ignore)
+                    // Nothing is left to choose, e.g. once-only choices that were all chosen.
+                    None if !choices.is_empty() && choices.iter().all(|c| c.hidden) => {
+                        self.choice_indexes = Some(vec![]);
+                        choices = vec![];
+                        next_pc = *end;
                     }
-                    Stmt::Set {
-                        variable_name,
-                        expression,
-                    } => {
-                        let new_value = eval_with_context(expression, &self.context)
-                            .unwrap_or_else(|_| {
-                                panic!("Something went wrong evaluating '{expression}'")
-                            });
-
-                        self.index_stack.set_top(index + 1);
-                        // Some(Event::Ignore)
-                        Some(Event::Set {
+                    _ => self.choice_indexes = Some(choice_indexes),
+                }
+
+                Event::Dialogue {
+                    character_id: character_id.to_owned(),
+                    speaker: speaker.to_owned(),
+                    text,
+                    spans,
+                    portrait_layers,
+                    line_id: line_id.to_owned(),
+                    voice_path: resolve_voice_path(
+                        self.voice_pattern.as_deref(),
+                        voice.as_deref(),
+                        character_id.as_deref(),
+                        line_id.as_deref(),
+                    ),
+                    choices,
+                }
+            }
+            Op::Choice { .. } | Op::Nop => Event::Ignore,
+            Op::Label { name } => {
+                visit(&mut self.visits, name);
+                Event::Ignore
+            }
+            Op::Return => {
+                leave = true;
+                Event::Ignore
+            }
+            Op::End => {
+                self.frames.clear();
+                self.choice_indexes = None;
+                return Some(Event::End);
+            }
+            Op::Alternatives {
+                alternation,
+                targets,
+                end,
+                ..
+            } => {
+                let site = self.site_id(timeline, pc);
+                next_pc = match self.pick_alternative(*alternation, site, targets.len(), true) {
+                    Some(alternative) => targets[alternative],
+                    None => *end,
+                };
+                Event::Ignore
+            }
+            Op::Match {
+                expression,
+                cases,
+                default,
+            } => {
+                let value = expression.eval(&self.script_context()).unwrap_or_else(|_| {
+                    panic!("Something went wrong evaluating '{}'", expression.source)
+                });
+                next_pc = cases
+                    .iter()
+                    .find(|(case, _)| *case == value)
+                    .map_or(*default, |(_, target)| *target);
+                Event::Ignore
+            }
+            Op::Jump { target } => {
+                next_pc = *target;
+                Event::Ignore
+            }
+            Op::JumpIfFalse { condition, target } => {
+                let evaluation = condition
+                    .eval_boolean(&self.script_context())
+                    .unwrap_or_else(|_| panic!("Error evaluating '{}'", condition.source));
+                if !evaluation {
+                    next_pc = *target;
+                }
+                Event::Ignore
+            }
+            Op::Call {
+                timeline,
+                jump,
+                arguments,
+            } => {
+                let locals = arguments
+                    .iter()
+                    .map(|(name, expression)| {
+                        let value = expression.eval(&self.script_context()).unwrap_or_else(|_| {
+                            panic!("Something went wrong evaluating '{}'", expression.source)
+                        });
+                        (name.to_owned(), value)
+                    })
+                    .collect();
+                call = Some((*timeline, *jump, locals));
+                Event::Ignore
+            }
+            Op::Set {
+                variable_name,
+                expression,
+            } => {
+                let new_value = expression.eval(&self.script_context()).unwrap_or_else(|_| {
+                    panic!("Something went wrong evaluating '{}'", expression.source)
+                });
+
+                // Locals shadow variables, so they're what the timeline sets.
+                match self
+                    .frames
+                    .last_mut()
+                    .and_then(|frame| frame.locals.get_mut(variable_name))
+                {
+                    Some(local) => {
+                        *local = new_value;
+                        Event::Ignore
+                    }
+                    None => {
+                        // Character variables are kept by the server, the rest by the host.
+                        if let Some(variable) = self.variables.get_mut(variable_name) {
+                            *variable = new_value.to_owned();
+                        }
+                        Event::Set {
                             variable_name: variable_name.to_owned(),
                             new_value,
-                        })
+                        }
                     }
-                };
-
-                if timeline.len() <= *self.index_stack.peek().unwrap() {
-                    self.timeline_stack.pop();
-                    self.index_stack.pop();
                 }
-
-                if let Some(new_timeline_name) = new_timeline_name {
-                    if jump.unwrap() {
-                        self.timeline_stack.pop();
-                        self.index_stack.pop();
+            }
+            Op::Show {
+                character_id,
+                expression,
+                position,
+            } => {
+                if let Some(expression) = expression {
+                    set_expression(
+                        &self.characters,
+                        &mut self.character_expressions,
+                        &mut self.character_outfits,
+                        character_id,
+                        expression,
+                    );
+                }
+                let position = match self.scene.get_mut(character_id) {
+                    Some(staged) => {
+                        if position.is_some() {
+                            staged.position = position.to_owned();
+                        }
+                        staged.position.to_owned()
                     }
-
-                    self.timelines
-                        .get(&new_timeline_name)
-                        .unwrap_or_else(|| panic!("Timeline '{new_timeline_name}' not found."));
-                    self.timeline_stack.push(new_timeline_name);
-                    self.index_stack.push(0);
+                    None => {
+                        self.scene.characters.push(StagedCharacter {
+                            character_id: character_id.to_owned(),
+                            position: position.to_owned(),
+                        });
+                        position.to_owned()
+                    }
+                };
+                Event::SceneChanged(SceneChange::Shown {
+                    character_id: character_id.to_owned(),
+                    position,
+                    portrait_layers: self.portrait_layers(character_id),
+                })
+            }
+            Op::Hide { character_id } => {
+                self.scene
+                    .characters
+                    .retain(|c| c.character_id != *character_id);
+                Event::SceneChanged(SceneChange::Hidden {
+                    character_id: character_id.to_owned(),
+                })
+            }
+            Op::Move {
+                character_id,
+                position,
+            } => match self.scene.get_mut(character_id) {
+                Some(staged) => {
+                    staged.position = Some(position.to_owned());
+                    Event::SceneChanged(SceneChange::Moved {
+                        character_id: character_id.to_owned(),
+                        position: position.to_owned(),
+                    })
                 }
+                // Moving a character who isn't on stage shows them there.
+                None => {
+                    self.scene.characters.push(StagedCharacter {
+                        character_id: character_id.to_owned(),
+                        position: Some(position.to_owned()),
+                    });
+                    Event::SceneChanged(SceneChange::Shown {
+                        character_id: character_id.to_owned(),
+                        position: Some(position.to_owned()),
+                        portrait_layers: self.portrait_layers(character_id),
+                    })
+                }
+            },
+        };
 
-                // self.timeline_stack.push(todo!());
+        // A jump replaces the timeline it's in, a call returns to it.
+        if leave || next_pc >= code.len() || matches!(call, Some((_, true, _))) {
+            self.frames.pop();
+        } else {
+            self.frames.last_mut().unwrap().pc = next_pc;
+        }
 
-                event
+        if let Some((timeline, _, locals)) = call {
+            if self.program.code(timeline).is_none() {
+                panic!("Timeline '{}' not found.", self.program.name(timeline));
             }
-            None => None,
+            self.frames.push(Frame {
+                timeline,
+                pc: 0,
+                locals,
+            });
+            visit(&mut self.visits, self.program.name(timeline));
         }
+
+        Some(event)
     }
 }
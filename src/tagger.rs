@@ -0,0 +1,155 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+};
+
+use pest::{error::ErrorVariant, iterators::Pair, Parser as PestParser};
+
+use crate::parser::{script_entries, Rule, ScriptParser};
+
+pub const LINE_TAG_PREFIX: &str = "#line:";
+
+/// Collects the line IDs already present in `input`.
+pub fn line_ids(input: &str) -> Result<HashSet<String>, pest::error::Error<Rule>> {
+    let mut ids = HashSet::new();
+    for pair in ScriptParser::parse(Rule::document, input)?.flatten() {
+        if pair.as_rule() == Rule::line_id {
+            ids.insert(pair.as_str().to_owned());
+        }
+    }
+    Ok(ids)
+}
+
+/// Appends a `#line:` tag to every dialogue line and choice in `input` that doesn't have one.
+///
+/// New IDs are added to `ids`, which should hold every ID already in use so that tags stay
+/// unique across files. `seed` distinguishes files so that identical lines get different IDs.
+pub fn tag_lines(
+    input: &str,
+    seed: &str,
+    ids: &mut HashSet<String>,
+) -> Result<String, pest::error::Error<Rule>> {
+    let mut offsets = Vec::new();
+    for pair in ScriptParser::parse(Rule::document, input)?.flatten() {
+        if matches!(pair.as_rule(), Rule::dialogue | Rule::choice) {
+            if let Some(offset) = untagged_offset(pair) {
+                offsets.push(offset);
+            }
+        }
+    }
+    offsets.sort_unstable();
+
+    let mut output = String::with_capacity(input.len() + offsets.len() * 16);
+    let mut last = 0;
+    for offset in offsets {
+        output.push_str(&input[last..offset]);
+        let id = new_line_id(seed, &input[last..offset], ids);
+        output.push_str(&format!(" {LINE_TAG_PREFIX}{id}"));
+        last = offset;
+    }
+    output.push_str(&input[last..]);
+
+    Ok(output)
+}
+
+/// Tags every untagged line in the scripts under `dir_name` in place.
+///
+/// Returns the number of lines that were tagged.
+pub fn tag_dir(dir_name: &str) -> Result<usize, pest::error::Error<Rule>> {
+    let files = read_scripts(dir_name)?;
+    let mut ids = HashSet::new();
+    for (_, contents) in &files {
+        ids.extend(line_ids(contents)?);
+    }
+
+    let mut count = 0;
+    for (filename, contents) in files {
+        let before = ids.len();
+        let tagged =
+            tag_lines(&contents, &filename, &mut ids).map_err(|e| e.with_path(&filename))?;
+        if ids.len() > before {
+            count += ids.len() - before;
+            fs::write(&filename, tagged)
+                .unwrap_or_else(|_| panic!("Something went wrong writing '{filename}'."));
+        }
+    }
+
+    Ok(count)
+}
+
+/// Checks that no line ID is used twice in the scripts under `dir_name`, e.g. by a copied line,
+/// as lines with the same ID share their saved state.
+pub fn check_line_ids(dir_name: &str) -> Result<(), pest::error::Error<Rule>> {
+    read_scripts(dir_name).map(|_| ())
+}
+
+/// Reads the scripts under `dir_name`, checking that their line IDs are unique.
+fn read_scripts(dir_name: &str) -> Result<Vec<(String, String)>, pest::error::Error<Rule>> {
+    let mut files = Vec::new();
+    // Where each ID was first seen, as `file:line`.
+    let mut sites: HashMap<String, String> = HashMap::new();
+
+    for entry in script_entries(dir_name) {
+        let filename = entry.path().to_str().unwrap().to_owned();
+        let contents = fs::read_to_string(&filename)
+            .unwrap_or_else(|_| panic!("Something went wrong reading '{filename}'."));
+        let pairs =
+            ScriptParser::parse(Rule::document, &contents).map_err(|e| e.with_path(&filename))?;
+        for pair in pairs.flatten() {
+            if pair.as_rule() != Rule::line_id {
+                continue;
+            }
+            let line = pair.as_span().start_pos().line_col().0;
+            if let Some(site) = sites.insert(pair.as_str().to_owned(), format!("{filename}:{line}"))
+            {
+                let message = format!(
+                    "Duplicate line ID '{}', already used at {site}.",
+                    pair.as_str()
+                );
+                return Err(pest::error::Error::new_from_span(
+                    ErrorVariant::CustomError { message },
+                    pair.as_span(),
+                )
+                .with_path(&filename));
+            }
+        }
+        files.push((filename, contents));
+    }
+
+    Ok(files)
+}
+
+/// Returns where a tag should be inserted for a dialogue or choice, or `None` if it already
+/// has one.
+fn untagged_offset(pair: Pair<Rule>) -> Option<usize> {
+    let mut offset = None;
+    for inner_pair in pair.into_inner() {
+        match inner_pair.as_rule() {
+            Rule::line_tag => return None,
+            Rule::speaker
+            | Rule::ident
+            | Rule::alias
+            | Rule::expression
+            | Rule::text
+            | Rule::voice
+            | Rule::bool_expr
+            | Rule::fallback => offset = Some(inner_pair.as_span().end()),
+            _ => (),
+        }
+    }
+    offset
+}
+
+fn new_line_id(seed: &str, context: &str, ids: &mut HashSet<String>) -> String {
+    let mut attempt: u64 = 0;
+    loop {
+        let mut hasher = DefaultHasher::new();
+        (seed, context, ids.len(), attempt).hash(&mut hasher);
+        let id = format!("{:06x}", hasher.finish() & 0xff_ffff);
+        if ids.insert(id.to_owned()) {
+            return id;
+        }
+        attempt += 1;
+    }
+}
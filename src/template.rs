@@ -0,0 +1,132 @@
+//! Templates in dialogue and choice text.
+//!
+//! `{expression}` is replaced by the value of the expression, optionally followed by a format
+//! spec as in `{expression:spec}` (see [`crate::format`]). `{{` and `}}` are literal braces.
+//!
+//! `{~Hi|Hello|Hey}`, `{&Morning|Noon|Night}` and `{!First time|Again?}` are alternatives: one of
+//! the options separated by `|` is picked each time the text is shown (see [`Alternation`]).
+//! Templates with a `|` are always alternatives, while others that are valid expressions are
+//! expressions, so `{!met_before}` is a negation. A leading space makes an expression of the
+//! rest, as in `{ !met_before || rich }`.
+//! The string escapes of the grammar (`\n`, `\"`, `\u00e9`, ...) are processed in the literal
+//! parts of the text only, so an escaped brace never starts a template.
+
+use evalexpr::build_operator_tree;
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_until},
+    combinator::{map, value},
+    multi::many0,
+    sequence::delimited,
+    IResult,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::format;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece<'a> {
+    Literal(&'a str),
+    Expression(&'a str),
+    Alternatives(Alternation, Vec<&'a str>),
+    /// A brace without its pair, which is kept as is.
+    Unbalanced(&'a str),
+}
+
+/// How one of several alternatives is picked each time they're reached.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alternation {
+    /// Each in turn, then the last one forever.
+    Sequence,
+    /// Each in turn, starting over after the last one.
+    Cycle,
+    /// A random one each time.
+    Shuffle,
+    /// Each in turn, then none.
+    Once,
+}
+
+impl Alternation {
+    /// The alternative to pick out of `len` after `visits` earlier visits, if any. `random(n)`
+    /// returns a number in `0..n`.
+    pub fn pick(
+        self,
+        visits: usize,
+        len: usize,
+        random: impl FnOnce(usize) -> usize,
+    ) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        match self {
+            Alternation::Sequence => Some(visits.min(len - 1)),
+            Alternation::Cycle => Some(visits % len),
+            Alternation::Shuffle => Some(random(len)),
+            Alternation::Once => (visits < len).then_some(visits),
+        }
+    }
+}
+
+fn template(template: &str) -> Piece<'_> {
+    let alternation = match template.chars().next() {
+        Some('~') => Alternation::Shuffle,
+        Some('&') => Alternation::Cycle,
+        Some('!') => Alternation::Once,
+        _ => return Piece::Expression(template),
+    };
+    if !template.contains('|') && build_operator_tree(format::split_spec(template).0).is_ok() {
+        return Piece::Expression(template);
+    }
+    Piece::Alternatives(alternation, template[1..].split('|').collect())
+}
+
+fn piece(input: &str) -> IResult<&str, Piece<'_>> {
+    alt((
+        value(Piece::Literal("{"), tag("{{")),
+        value(Piece::Literal("}"), tag("}}")),
+        map(delimited(tag("{"), take_until("}"), tag("}")), template),
+        map(is_not("{}"), Piece::Literal),
+        map(alt((tag("{"), tag("}"))), Piece::Unbalanced),
+    ))(input)
+}
+
+/// Splits `input` into literal text and template expressions.
+pub fn pieces(input: &str) -> Vec<Piece<'_>> {
+    many0(piece)(input)
+        .map(|(_, pieces)| pieces)
+        .unwrap_or_default()
+}
+
+/// Processes the string escapes of the grammar.
+pub fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('r') => output.push('\r'),
+            Some('t') => output.push('\t'),
+            Some('b') => output.push('\u{8}'),
+            Some('f') => output.push('\u{c}'),
+            Some('u') => {
+                let hex: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    Some(c) => output.push(c),
+                    None => {
+                        output.push_str("\\u");
+                        output.push_str(&hex);
+                    }
+                }
+            }
+            Some(c) => output.push(c),
+            None => output.push('\\'),
+        }
+    }
+
+    output
+}
@@ -7,19 +7,23 @@ use std::collections::HashMap;
 #[test]
 fn test_dialogue_pair() {
     assert_eq!(
-        parser::Parser::new(vec![]).dialogue_pair(
-            parser::ScriptParser::parse(parser::Rule::dialogue, r#""Hello world!""#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .dialogue_pair(
+                parser::ScriptParser::parse(parser::Rule::dialogue, r#""Hello world!""#)
+                    .unwrap()
+                    .next()
+                    .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
@@ -36,18 +40,18 @@ fn test_dialogue_pair() {
             parser::ScriptParser::parse(parser::Rule::dialogue, r#"Elira "Hello world!""#)
                 .unwrap()
                 .next()
-                .unwrap()
-        ),
+                .unwrap(),
+            &mut parser::ParseState::default()
+        )
+        .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::from([(
-                    "default".to_owned(),
-                    "test_images/elira1.png".to_owned()
-                )]),
                 character_id: Some("Elira".to_owned()),
                 speaker: Some("Elira".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
@@ -64,15 +68,18 @@ fn test_dialogue_pair() {
             parser::ScriptParser::parse(parser::Rule::dialogue, r#"Elira "Hello world!""#)
                 .unwrap()
                 .next()
-                .unwrap()
-        ),
+                .unwrap(),
+            &mut parser::ParseState::default()
+        )
+        .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: Some("Elira".to_owned()),
                 speaker: Some("Elira".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
@@ -92,15 +99,18 @@ fn test_dialogue_pair() {
             )
             .unwrap()
             .next()
-            .unwrap()
-        ),
+            .unwrap(),
+            &mut parser::ParseState::default()
+        )
+        .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: Some("Elira".to_owned()),
                 speaker: Some("Sheesh Dragon".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
@@ -117,67 +127,84 @@ fn test_dialogue_pair() {
             parser::ScriptParser::parse(parser::Rule::dialogue, r#"Sheesh "Hello world!""#)
                 .unwrap()
                 .next()
-                .unwrap()
-        ),
+                .unwrap(),
+            &mut parser::ParseState::default()
+        )
+        .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: Some("Elira".to_owned()),
                 speaker: Some("Sheesh Dragon".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).dialogue_pair(
-            parser::ScriptParser::parse(parser::Rule::dialogue, r#""Elira" "Hello world!""#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .dialogue_pair(
+                parser::ScriptParser::parse(parser::Rule::dialogue, r#""Elira" "Hello world!""#)
+                    .unwrap()
+                    .next()
+                    .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: Some("Elira".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue
         ]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).dialogue_pair(
-            parser::ScriptParser::parse(
-                parser::Rule::dialogue,
-                r#""Elira" "Hello world!"
+        parser::Parser::new(vec![])
+            .dialogue_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::dialogue,
+                    r#""Elira" "Hello world!"
 -- "First"
 -- "Second""#
+                )
+                .unwrap()
+                .next()
+                .unwrap(),
+                &mut parser::ParseState::default()
             )
-            .unwrap()
-            .next()
-            .unwrap()
-        ),
+            .unwrap(),
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: Some("Elira".to_owned()),
-                text: "Hello world!".to_owned()
+                text: "Hello world!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::Choice {
                 text: "First".to_owned(),
                 condition: None,
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::EndChoice,
             parser::Stmt::Choice {
                 text: "Second".to_owned(),
                 condition: None,
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::EndChoice,
             parser::Stmt::EndDialogue
@@ -188,84 +215,116 @@ fn test_dialogue_pair() {
 #[test]
 fn test_choice_pair() {
     assert_eq!(
-        parser::Parser::new(vec![]).choice_pair(
-            parser::ScriptParser::parse(parser::Rule::choice, r#"-- "This is a choice.""#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .choice_pair(
+                parser::ScriptParser::parse(parser::Rule::choice, r#"-- "This is a choice.""#)
+                    .unwrap()
+                    .next()
+                    .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::Choice {
                 text: "This is a choice.".to_owned(),
                 condition: None,
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::EndChoice
         ]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).choice_pair(
-            parser::ScriptParser::parse(parser::Rule::choice, r#"-- "This is a choice." if true"#)
+        parser::Parser::new(vec![])
+            .choice_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::choice,
+                    r#"-- "This is a choice." if true"#
+                )
                 .unwrap()
                 .next()
-                .unwrap()
-        ),
+                .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::Choice {
                 text: "This is a choice.".to_owned(),
                 condition: Some("true".to_owned()),
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::EndChoice
         ]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).choice_pair(
-            parser::ScriptParser::parse(parser::Rule::choice, r#"-- "This is a choice." if true"#)
+        parser::Parser::new(vec![])
+            .choice_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::choice,
+                    r#"-- "This is a choice." if true"#
+                )
                 .unwrap()
                 .next()
-                .unwrap()
-        ),
+                .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::Choice {
                 text: "This is a choice.".to_owned(),
                 condition: Some("true".to_owned()),
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::EndChoice
         ]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).choice_pair(
-            parser::ScriptParser::parse(
-                parser::Rule::choice,
-                r#"-- "This is a choice."
+        parser::Parser::new(vec![])
+            .choice_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::choice,
+                    r#"-- "This is a choice."
 	"Nested"
 	"Nested again""#
+                )
+                .unwrap()
+                .next()
+                .unwrap(),
+                &mut parser::ParseState::default()
             )
-            .unwrap()
-            .next()
-            .unwrap()
-        ),
+            .unwrap(),
         vec![
             parser::Stmt::Choice {
                 text: "This is a choice.".to_owned(),
                 condition: None,
+                line_id: None,
+                once: false,
+                fallback: false
             },
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Nested".to_owned()
+                text: "Nested".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue,
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Nested again".to_owned()
+                text: "Nested again".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue,
             parser::Stmt::EndChoice
@@ -276,12 +335,15 @@ fn test_choice_pair() {
 #[test]
 fn test_if_pair() {
     assert_eq!(
-        parser::Parser::new(vec![]).if_pair(
-            parser::ScriptParser::parse(parser::Rule::if_stmt, r#"if 1 == 1:"#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .if_pair(
+                parser::ScriptParser::parse(parser::Rule::if_stmt, r#"if 1 == 1:"#)
+                    .unwrap()
+                    .next()
+                    .unwrap(),
+                &mut parser::ParseState::default()
+            )
+            .unwrap(),
         vec![
             parser::Stmt::If {
                 condition: "1 == 1".to_owned()
@@ -291,26 +353,30 @@ fn test_if_pair() {
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).if_pair(
-            parser::ScriptParser::parse(
-                parser::Rule::if_stmt,
-                r#"if true:
+        parser::Parser::new(vec![])
+            .if_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::if_stmt,
+                    r#"if true:
 	"Nested""#
+                )
+                .unwrap()
+                .next()
+                .unwrap(),
+                &mut parser::ParseState::default()
             )
-            .unwrap()
-            .next()
-            .unwrap()
-        ),
+            .unwrap(),
         vec![
             parser::Stmt::If {
                 condition: "true".to_owned()
             },
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Nested".to_owned()
+                text: "Nested".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue,
             parser::Stmt::EndIf
@@ -321,41 +387,75 @@ fn test_if_pair() {
 #[test]
 fn test_call_pair() {
     assert_eq!(
-        parser::Parser::new(vec![]).call_pair(
-            parser::ScriptParser::parse(parser::Rule::call, r#"call "foo""#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .call_pair(
+                parser::ScriptParser::parse(parser::Rule::call, r#"call "foo""#)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+            )
+            .unwrap(),
         vec![parser::Stmt::Call {
             jump: false,
-            timeline_name: "foo".to_owned()
+            timeline_name: "foo".to_owned(),
+            arguments: vec![]
+        }]
+    );
+
+    assert_eq!(
+        parser::Parser::new(vec![])
+            .call_pair(
+                parser::ScriptParser::parse(parser::Rule::call, r#"jump "foo""#)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+            )
+            .unwrap(),
+        vec![parser::Stmt::Call {
+            jump: true,
+            timeline_name: "foo".to_owned(),
+            arguments: vec![]
         }]
     );
 
     assert_eq!(
-        parser::Parser::new(vec![]).call_pair(
-            parser::ScriptParser::parse(parser::Rule::call, r#"jump "foo""#)
+        parser::Parser::new(vec![])
+            .call_pair(
+                parser::ScriptParser::parse(
+                    parser::Rule::call,
+                    r#"call "greet"(who = "Elira", mood=mood + 1)"#
+                )
                 .unwrap()
                 .next()
                 .unwrap()
-        ),
+            )
+            .unwrap(),
         vec![parser::Stmt::Call {
-            jump: true,
-            timeline_name: "foo".to_owned()
+            jump: false,
+            timeline_name: "greet".to_owned(),
+            arguments: vec![
+                ("who".to_owned(), r#""Elira""#.to_owned()),
+                ("mood".to_owned(), "mood + 1".to_owned())
+            ]
         }]
     );
+
+    assert!(parser::Parser::new(vec![])
+        .parse(r#"call "greet"(who="Elira", who="Kael")"#)
+        .is_err());
 }
 
 #[test]
 fn test_set_pair() {
     assert_eq!(
-        parser::Parser::new(vec![]).set_pair(
-            parser::ScriptParser::parse(parser::Rule::set, r#"foo = "bar""#)
-                .unwrap()
-                .next()
-                .unwrap()
-        ),
+        parser::Parser::new(vec![])
+            .set_pair(
+                parser::ScriptParser::parse(parser::Rule::set, r#"foo = "bar""#)
+                    .unwrap()
+                    .next()
+                    .unwrap()
+            )
+            .unwrap(),
         vec![parser::Stmt::Set {
             variable_name: "foo".to_owned(),
             expression: r#""bar""#.to_owned()
@@ -372,10 +472,11 @@ fn test_parse() {
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Hello World".to_owned()
+                text: "Hello World".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue,
         ]
@@ -391,10 +492,11 @@ fn test_parse_file() {
         vec![
             parser::Stmt::Dialogue {
                 expression: None,
-                portraits: HashMap::new(),
                 character_id: None,
                 speaker: None,
-                text: "Hello World!".to_owned()
+                text: "Hello World!".to_owned(),
+                line_id: None,
+                voice: None
             },
             parser::Stmt::EndDialogue,
         ]
@@ -411,10 +513,11 @@ fn test_parse_dir() {
                 Timeline::from([
                     parser::Stmt::Dialogue {
                         expression: None,
-                        portraits: HashMap::new(),
                         character_id: None,
                         speaker: None,
-                        text: "Hello World!".to_owned()
+                        text: "Hello World!".to_owned(),
+                        line_id: None,
+                        voice: None
                     },
                     parser::Stmt::EndDialogue
                 ])
@@ -424,10 +527,11 @@ fn test_parse_dir() {
                 Timeline::from([
                     parser::Stmt::Dialogue {
                         expression: None,
-                        portraits: HashMap::new(),
                         character_id: None,
                         speaker: None,
-                        text: "Nested Timeline".to_owned()
+                        text: "Nested Timeline".to_owned(),
+                        line_id: None,
+                        voice: None
                     },
                     parser::Stmt::EndDialogue
                 ])
@@ -449,5 +553,1708 @@ fn test_characters_from_json() {
     )
 }
 
+#[test]
+fn test_line_tags() {
+    assert_eq!(
+        parser::Parser::new(vec![])
+            .parse(
+                r#""Hello world!" #line:a1b2c3
+-- "First" if true #line:d4e5f6"#
+            )
+            .unwrap(),
+        vec![
+            parser::Stmt::Dialogue {
+                expression: None,
+                character_id: None,
+                speaker: None,
+                text: "Hello world!".to_owned(),
+                line_id: Some("a1b2c3".to_owned()),
+                voice: None
+            },
+            parser::Stmt::Choice {
+                text: "First".to_owned(),
+                condition: Some("true".to_owned()),
+                line_id: Some("d4e5f6".to_owned()),
+                once: false,
+                fallback: false
+            },
+            parser::Stmt::EndChoice,
+            parser::Stmt::EndDialogue
+        ]
+    );
+}
+
+#[test]
+fn test_tag_lines() {
+    let input = r#""Hello" #line:a1b2c3
+"World" // Greeting
+-- "First"
+	"Nested"
+-- else
+	"Fallback""#;
+    let mut ids = tagger::line_ids(input).unwrap();
+    let tagged = tagger::tag_lines(input, "start", &mut ids).unwrap();
+
+    assert_eq!(ids.len(), 6);
+    assert_eq!(tagger::line_ids(&tagged).unwrap(), ids);
+    assert_eq!(
+        tagger::tag_lines(&tagged, "start", &mut ids).unwrap(),
+        tagged
+    );
+    assert!(tagged.starts_with("\"Hello\" #line:a1b2c3\n\"World\" #line:"));
+    assert!(tagged.contains(" // Greeting\n-- \"First\" #line:"));
+    assert!(tagged.contains("\n-- else #line:"));
+    assert!(parser::Parser::new(vec![])
+        .parse(&tagged)
+        .unwrap()
+        .iter()
+        .any(|stmt| matches!(
+            stmt,
+            parser::Stmt::Choice {
+                fallback: true,
+                line_id: Some(_),
+                ..
+            }
+        )));
+}
+
+#[test]
+fn test_duplicate_line_ids() {
+    let is_duplicate = |error: pest::error::Error<parser::Rule>| {
+        matches!(
+            error.variant,
+            pest::error::ErrorVariant::CustomError { ref message }
+                if message.starts_with("Duplicate line ID 'hello', already used at test_duplicates")
+        )
+    };
+    assert!(is_duplicate(
+        tagger::tag_dir("test_duplicates").unwrap_err()
+    ));
+    assert!(is_duplicate(
+        parser::Parser::new(vec![])
+            .parse_dir("test_duplicates")
+            .unwrap_err()
+    ));
+    // Nothing was tagged.
+    assert_eq!(
+        std::fs::read_to_string("test_duplicates/chapter/one.nobela").unwrap(),
+        "\"Hi.\"\n\"Hello again.\" #line:hello\n"
+    );
+}
+
+#[test]
+fn test_voice() {
+    assert_eq!(
+        parser::Parser::new(vec![])
+            .parse(r#""Hello world!" voice "intro.ogg" #line:a1b2c3"#)
+            .unwrap()[0],
+        parser::Stmt::Dialogue {
+            expression: None,
+            character_id: None,
+            speaker: None,
+            text: "Hello world!".to_owned(),
+            line_id: Some("a1b2c3".to_owned()),
+            voice: Some("intro.ogg".to_owned())
+        }
+    );
+
+    assert_eq!(
+        voice::resolve_voice_path(
+            Some(voice::DEFAULT_VOICE_PATTERN),
+            None,
+            Some("Elira"),
+            Some("a1b2c3")
+        ),
+        Some("voice/Elira/a1b2c3.ogg".to_owned())
+    );
+    assert_eq!(
+        voice::resolve_voice_path(
+            Some(voice::DEFAULT_VOICE_PATTERN),
+            Some("intro.ogg"),
+            Some("Elira"),
+            Some("a1b2c3")
+        ),
+        Some("intro.ogg".to_owned())
+    );
+    assert_eq!(
+        voice::resolve_voice_path(
+            Some(voice::DEFAULT_VOICE_PATTERN),
+            None,
+            None,
+            Some("a1b2c3")
+        ),
+        None
+    );
+
+    let timelines = Timelines::from([(
+        "start".to_owned(),
+        parser::Parser::new(vec![Character::new(
+            "Elira",
+            "Elira",
+            HashMap::new(),
+            HashMap::new(),
+        )])
+        .parse(
+            r#"Elira "Hello world!" #line:a1b2c3
+"Narration""#,
+        )
+        .unwrap(),
+    )]);
+    assert_eq!(
+        voice::missing_voice_files(&timelines, voice::DEFAULT_VOICE_PATTERN, "test_files"),
+        vec![voice::MissingVoice {
+            timeline_name: "start".to_owned(),
+            index: 0,
+            line_id: Some("a1b2c3".to_owned()),
+            voice_path: Some("voice/Elira/a1b2c3.ogg".to_owned())
+        }]
+    );
+}
+
+#[test]
+fn test_markup() {
+    let bold = markup::Tag {
+        name: "b".to_owned(),
+        value: None,
+    };
+    let color = markup::Tag {
+        name: "color".to_owned(),
+        value: Some("red".to_owned()),
+    };
+
+    assert_eq!(
+        markup::parse("I [b]really [color=red]mean[/color][/b] it[wait=0.5]...[shake][[ok]")
+            .unwrap(),
+        vec![
+            markup::Span::Text {
+                text: "I ".to_owned(),
+                styles: vec![]
+            },
+            markup::Span::Text {
+                text: "really ".to_owned(),
+                styles: vec![bold.to_owned()]
+            },
+            markup::Span::Text {
+                text: "mean".to_owned(),
+                styles: vec![bold.to_owned(), color]
+            },
+            markup::Span::Text {
+                text: " it".to_owned(),
+                styles: vec![]
+            },
+            markup::Span::Control(markup::Tag {
+                name: "wait".to_owned(),
+                value: Some("0.5".to_owned())
+            }),
+            markup::Span::Text {
+                text: "...".to_owned(),
+                styles: vec![]
+            },
+            markup::Span::Control(markup::Tag {
+                name: "shake".to_owned(),
+                value: None
+            }),
+            markup::Span::Text {
+                text: "[ok]".to_owned(),
+                styles: vec![]
+            },
+        ]
+    );
+    assert_eq!(
+        markup::plain_text(&markup::parse("[b]Hi[/b][wait=1] there").unwrap()),
+        "Hi there"
+    );
+
+    assert!(markup::parse("[b]Hi").is_err());
+    assert!(markup::parse("Hi[/i]").is_err());
+    assert!(markup::parse("[b][i]Hi[/b][/i]").is_err());
+    assert!(markup::parse("[wait=soon]").is_err());
+    assert!(markup::parse("[b").is_err());
+
+    let error = parser::Parser::new(vec![])
+        .parse(r#""Hello [b]world""#)
+        .unwrap_err();
+    assert_eq!(error.line_col, pest::error::LineColLocation::Pos((1, 8)));
+    let error = parser::Parser::new(vec![])
+        .parse("\"Hello.\"\n-- \"[i]Bye\"")
+        .unwrap_err();
+    assert_eq!(error.line_col, pest::error::LineColLocation::Pos((2, 5)));
+
+    // Template values are text, even when they look like markup.
+    let timeline = parser::Parser::new(vec![])
+        .parse("\"[b]{name}[/b] says {quote}\"\n-- \"[i]{quote}[/i]\"")
+        .unwrap();
+    let context = evalexpr::context_map! {
+        "name" => "[i]Elira",
+        "quote" => "[wait=1] [b",
+    }
+    .unwrap();
+    let mut server =
+        server::Server::new(Timelines::from([("start".to_owned(), timeline)]), context);
+    server.start("start", 0);
+    match server.next() {
+        Some(server::Event::Dialogue {
+            text,
+            spans,
+            choices,
+            ..
+        }) => {
+            assert_eq!(text, "[i]Elira says [wait=1] [b");
+            assert_eq!(
+                spans,
+                vec![
+                    markup::Span::Text {
+                        text: "[i]Elira".to_owned(),
+                        styles: vec![bold]
+                    },
+                    markup::Span::Text {
+                        text: " says [wait=1] [b".to_owned(),
+                        styles: vec![]
+                    }
+                ]
+            );
+            assert_eq!(choices[0].text, "[wait=1] [b");
+            assert_eq!(
+                choices[0].spans,
+                vec![markup::Span::Text {
+                    text: "[wait=1] [b".to_owned(),
+                    styles: vec![markup::Tag {
+                        name: "i".to_owned(),
+                        value: None
+                    }]
+                }]
+            );
+        }
+        _ => panic!("Expected dialogue."),
+    }
+}
+
+#[test]
+fn test_render_text() {
+    let context = evalexpr::context_map! {
+        "name" => "Elira",
+        "gold" => 10,
+    }
+    .unwrap();
+    let mut server = server::Server::new(Timelines::new(), context);
+
+    assert_eq!(
+        server.render_text(r#"{name} has {gold * 2} gold."#),
+        Ok("Elira has 20 gold.".to_owned())
+    );
+    assert_eq!(
+        server.render_text(r#"{{\"json\": {gold}}}\n\u00e9"#),
+        Ok("{\"json\": 10}\n\u{e9}".to_owned())
+    );
+    assert_eq!(
+        server.render_text("{missing} and {unclosed"),
+        Ok("{missing} and {unclosed".to_owned())
+    );
+    // A stray brace doesn't stop the templates after it.
+    assert_eq!(
+        server.render_text("a } b {gold}"),
+        Ok("a } b 10".to_owned())
+    );
+    assert_eq!(
+        server.render_text("Score: {gold} }:) {gold} {"),
+        Ok("Score: 10 }:) 10 {".to_owned())
+    );
+
+    server.set_strict_templates(true);
+    assert!(server.render_text("{missing}").is_err());
+    assert!(server.render_text("{unclosed").is_err());
+    assert!(server.render_text("a } b {gold}").is_err());
+    assert_eq!(server.render_text("{{}}"), Ok("{}".to_owned()));
+}
+
+#[test]
+fn test_server_is_send_sync() {
+    // Hosts keep the server in shared state, e.g. a game engine's resources.
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<server::Server>();
+}
+
+#[test]
+fn test_format() {
+    let context = evalexpr::context_map! {
+        "name" => "elira of the woods",
+        "gold" => 1234567,
+        "ratio" => 0.4567,
+        "count" => 7,
+        "third" => 1.0 / 3.0,
+    }
+    .unwrap();
+    let mut server = server::Server::new(Timelines::new(), context);
+    server.register_formatter("coins", |value| match value {
+        evalexpr::Value::Int(v) => Ok(format!("{v}c")),
+        _ => Err("Expected an integer.".to_owned()),
+    });
+
+    assert_eq!(
+        server.render_text(
+            "{gold:,} {ratio:.1%} {name:upper} {name:title} {count:02} {third:.2} {gold:coins}"
+        ),
+        Ok("1,234,567 45.7% ELIRA OF THE WOODS Elira Of The Woods 07 0.33 1234567c".to_owned())
+    );
+    assert_eq!(
+        server.render_text(r#"{"a:b"} {(1, "two")} {third:8.3}"#),
+        Ok("a:b 1, two    0.333".to_owned())
+    );
+
+    server.set_strict_templates(true);
+    assert!(server.render_text("{name:,}").is_err());
+    assert!(server.render_text("{gold:nope}").is_err());
+}
+
+#[test]
+fn test_plural() {
+    let context = evalexpr::context_map! {
+        "coins" => 1,
+        "gender" => "female",
+    }
+    .unwrap();
+    let mut server = server::Server::new(Timelines::new(), context);
+
+    assert_eq!(
+        server.render_text(
+            r#"{coins} {plural(coins, "coin", "coins")}, {plural(3, "coin", "coins")}, {pronoun(gender, "he", "she", "they")}"#
+        ),
+        Ok("1 coin, coins, she".to_owned())
+    );
+    assert_eq!(
+        server.render_text(
+            r#"{pronoun("nonbinary", "he", "she", "they")} {pronoun(0, "he", "she", "they")}"#
+        ),
+        Ok("they he".to_owned())
+    );
+
+    server.set_locale("ru-RU").unwrap();
+    assert_eq!(
+        server.render_text(
+            r#"{plural(21, "монета", "монеты", "монет")} {plural(3, "монета", "монеты", "монет")} {plural(11, "монета", "монеты", "монет")}"#
+        ),
+        Ok("монета монеты монет".to_owned())
+    );
+    assert!(server.set_locale("xx").is_err());
+    assert!(server.set_locale("not a locale").is_err());
+
+    use plural::PluralCategory::*;
+    for (locale, n, category) in [
+        ("fr", 0.0, One),
+        ("tr", 1.0, One),
+        ("tr", 2.0, Other),
+        ("pl", 22.0, Few),
+        ("pl", 25.0, Many),
+        ("ar", 0.0, Zero),
+        ("ja", 1.0, Other),
+        ("en", 1.5, Other),
+        ("en-US", 1.0, One),
+        ("pt_BR", 0.0, One),
+        ("hr", 21.0, One),
+        ("hr", 22.0, Few),
+        ("hr", 25.0, Other),
+        ("sr", 3.0, Few),
+        ("bs", 11.0, Other),
+        ("ro", 0.0, Few),
+        ("ro", 20.0, Other),
+        ("lt", 10.0, Other),
+        ("lt", 1.5, Many),
+        ("lv", 10.0, Zero),
+        ("sl", 102.0, Two),
+        ("hi", 0.0, One),
+        ("fa", 0.0, One),
+        ("cy", 3.0, Few),
+        ("cy", 6.0, Many),
+        ("ga", 7.0, Many),
+        ("be", 21.0, One),
+    ] {
+        assert_eq!(
+            plural::plural_category(locale, n),
+            Ok(category),
+            "{n} in '{locale}'"
+        );
+    }
+    assert_eq!(plural::plural_categories("tr-TR"), Ok(vec![One, Other]));
+    assert_eq!(
+        plural::plural_categories("ru"),
+        Ok(vec![One, Few, Many, Other])
+    );
+    assert_eq!(
+        plural::plural_categories("cy"),
+        Ok(vec![Zero, One, Two, Few, Many, Other])
+    );
+    assert!(plural::plural_category("xx", 1.0).is_err());
+}
+
+#[test]
+fn test_character_fields() {
+    let character: Character = serde_json::from_str(
+        r##"{
+            "id": "Elira",
+            "display_name": "Elira",
+            "aliases": {},
+            "portraits": {},
+            "name_color": "#ff8800",
+            "text_speed": 1.5,
+            "voice_blip": "blips/elira.ogg",
+            "metadata": { "age": 19 }
+        }"##,
+    )
+    .unwrap();
+    assert_eq!(character.name_color(), Some(&"#ff8800".to_owned()));
+    assert_eq!(character.text_speed(), Some(1.5));
+    assert_eq!(character.voice_blip(), Some(&"blips/elira.ogg".to_owned()));
+    assert_eq!(character.metadata()["age"], 19);
+
+    let mut server = server::Server::new(Timelines::new(), evalexpr::HashMapContext::new());
+    server.set_characters(vec![character]);
+    assert_eq!(
+        server.character("Elira").unwrap().name_color(),
+        Some(&"#ff8800".to_owned())
+    );
+    assert!(server.character("Nobody").is_none());
+
+    let character = &parser::characters_from_json("characters.json").unwrap()[0];
+    assert_eq!(character.name_color(), None);
+    assert!(character.metadata().is_null());
+}
+
+#[test]
+fn test_character_variables() {
+    let characters: Vec<Character> = serde_json::from_str(
+        r#"[{
+            "id": "Elira",
+            "display_name": "Elira",
+            "aliases": {},
+            "portraits": {},
+            "variables": { "affection": 0 }
+        }]"#,
+    )
+    .unwrap();
+    let parser = parser::Parser::new(characters.to_owned());
+    let timeline = parser
+        .parse(
+            r#"Elira.affection = Elira.affection + 5
+if Elira.affection > 3:
+	Elira "I like you.""#,
+        )
+        .unwrap();
+    assert!(parser.parse("Elira.trust = 1").is_err());
+
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(characters.to_owned());
+    assert_eq!(
+        server.variable("Elira.affection"),
+        Some(&evalexpr::Value::Int(0))
+    );
+    server.start("start", 0);
+
+    assert!(matches!(server.next(), Some(server::Event::Set { .. })));
+    let snapshot: server::Snapshot =
+        serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap();
+    assert_eq!(snapshot, server.snapshot());
+
+    let texts: Vec<String> = server
+        .filter_map(|event| match event {
+            server::Event::Dialogue { text, .. } => Some(text),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(texts, vec!["I like you.".to_owned()]);
+
+    let mut server = server::Server::new(
+        Timelines::from([(
+            "start".to_owned(),
+            parser.parse(r#"Elira "{Elira.affection}""#).unwrap(),
+        )]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(characters);
+    server.restore(server::Snapshot {
+        timeline_stack: vec!["start".to_owned()],
+        index_stack: vec![0],
+        ..snapshot
+    });
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::Dialogue { text, .. }) if text == "5"
+    ));
+}
+
+#[test]
+fn test_character_registry() {
+    let registry = CharacterRegistry::from_dir("test_files/characters", "test_files").unwrap();
+    assert_eq!(
+        registry
+            .characters()
+            .iter()
+            .map(|c| c.id().as_str())
+            .collect::<Vec<&str>>(),
+        vec!["Elira", "Kael", "Mira"]
+    );
+    assert_eq!(
+        registry.get("Elira").unwrap().get_alias_name("Sheesh"),
+        Some("Sheesh Dragon".to_owned())
+    );
+    assert_eq!(registry.get("Kael").unwrap().text_speed(), Some(0.8));
+    assert_eq!(registry.get("Mira").unwrap().metadata()["faction"], "guild");
+
+    let characters: Vec<Character> = serde_json::from_str(
+        r#"[
+            { "id": "Elira", "display_name": "Elira", "aliases": { "Kael": "Kael?" } },
+            { "id": "Elira", "display_name": "Elira" },
+            {
+                "id": "Mira",
+                "display_name": "Mira",
+                "aliases": { "Kael": "Kael!" },
+                "portraits": { "default": "images/missing.png" }
+            }
+        ]"#,
+    )
+    .unwrap();
+    let mut errors = CharacterRegistry::new(characters).validate("test_files");
+    errors.sort_by_key(|e| e.to_string());
+    assert_eq!(
+        errors,
+        vec![
+            CharacterError::AliasCollision {
+                alias: "Kael".to_owned(),
+                character_id: "Mira".to_owned(),
+                other_id: "Elira".to_owned(),
+            },
+            CharacterError::DuplicateId {
+                id: "Elira".to_owned()
+            },
+            CharacterError::MissingPortrait {
+                character_id: "Mira".to_owned(),
+                portrait: "default".to_owned(),
+                path: "images/missing.png".to_owned(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_portrait_layers() {
+    let characters: Vec<Character> = serde_json::from_str(
+        r#"[{
+            "id": "Elira",
+            "display_name": "Elira",
+            "layers": {
+                "base": "elira/base.png",
+                "outfits": { "uniform": "elira/uniform.png", "casual": "elira/casual.png" },
+                "expressions": { "neutral": "elira/neutral.png", "smile": "elira/smile.png" },
+                "default_outfit": "uniform",
+                "default_expression": "neutral"
+            }
+        }]"#,
+    )
+    .unwrap();
+    let parser = parser::Parser::new(characters.to_owned());
+    let timeline = parser
+        .parse(
+            r#"Elira "Hello."
+Elira smile + casual "Do you like it?"
+Elira "It's new.""#,
+        )
+        .unwrap();
+    assert!(matches!(
+        &timeline[2],
+        parser::Stmt::Dialogue { expression: Some(expression), .. } if expression == "smile+casual"
+    ));
+
+    let error = parser.parse(r#"Elira smlie "Hi.""#).unwrap_err();
+    assert!(matches!(
+        error.variant,
+        pest::error::ErrorVariant::CustomError { ref message }
+            if message == "Character 'Elira' has no portrait 'smlie'. Did you mean 'smile'?"
+    ));
+    assert_eq!(
+        error.line_col,
+        pest::error::LineColLocation::Span((1, 7), (1, 12))
+    );
+    assert!(matches!(
+        parser.parse("show Elira casual + frown").unwrap_err().variant,
+        pest::error::ErrorVariant::CustomError { ref message }
+            if message == "Character 'Elira' has no portrait 'frown'."
+    ));
+
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(characters);
+    server.start("start", 0);
+    let layers: Vec<Vec<String>> = server
+        .filter_map(|event| match event {
+            server::Event::Dialogue {
+                portrait_layers, ..
+            } => Some(portrait_layers),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        layers,
+        vec![
+            vec!["elira/base.png", "elira/uniform.png", "elira/neutral.png"],
+            vec!["elira/base.png", "elira/casual.png", "elira/smile.png"],
+            vec!["elira/base.png", "elira/casual.png", "elira/smile.png"],
+        ]
+    );
+}
+
+#[test]
+fn test_update_character() {
+    let timeline = parser::Parser::new(vec![Character::new(
+        "Elira",
+        "Elira",
+        HashMap::new(),
+        HashMap::new(),
+    )])
+    .parse(
+        r#"Elira "Hello."
+Elira "Hello again.""#,
+    )
+    .unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(vec![Character::new(
+        "Elira",
+        "Elira",
+        HashMap::new(),
+        HashMap::from([("default".to_owned(), "elira/old.png".to_owned())]),
+    )]);
+    server.start("start", 0);
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::Dialogue { portrait_layers, .. }) if portrait_layers == vec!["elira/old.png"]
+    ));
+
+    server.update_character(Character::new(
+        "Elira",
+        "Elira",
+        HashMap::new(),
+        HashMap::from([("default".to_owned(), "elira/new.png".to_owned())]),
+    ));
+    assert_eq!(server.character_registry().characters().len(), 1);
+    server.next();
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::Dialogue { portrait_layers, .. }) if portrait_layers == vec!["elira/new.png"]
+    ));
+}
+
+#[test]
+fn test_scene() {
+    let characters = vec![
+        Character::new(
+            "Elira",
+            "Elira",
+            HashMap::from([("Sheesh".to_owned(), "Sheesh".to_owned())]),
+            HashMap::from([
+                ("default".to_owned(), "elira/default.png".to_owned()),
+                ("smile".to_owned(), "elira/smile.png".to_owned()),
+            ]),
+        ),
+        Character::new("Kael", "Kael", HashMap::new(), HashMap::new()),
+    ];
+    let parser = parser::Parser::new(characters.to_owned());
+    let timeline = parser
+        .parse(
+            r#"show Elira smile at left
+show Kael
+settings = 1
+move Kael to center
+hide Elira
+show Sheesh"#,
+        )
+        .unwrap();
+    assert_eq!(
+        timeline[0],
+        parser::Stmt::Show {
+            character_id: "Elira".to_owned(),
+            expression: Some("smile".to_owned()),
+            position: Some("left".to_owned()),
+        }
+    );
+    assert_eq!(
+        timeline[1],
+        parser::Stmt::Show {
+            character_id: "Kael".to_owned(),
+            expression: None,
+            position: None,
+        }
+    );
+    assert!(
+        matches!(&timeline[2], parser::Stmt::Set { variable_name, .. } if variable_name == "settings")
+    );
+    assert!(parser.parse("show Nobody").is_err());
+
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(characters.to_owned());
+    server.start("start", 0);
+
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::SceneChanged(server::SceneChange::Shown { position: Some(position), portrait_layers, .. }))
+            if position == "left" && portrait_layers == vec!["elira/smile.png"]
+    ));
+    server.next();
+    server.next();
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::SceneChanged(server::SceneChange::Moved { position, .. })) if position == "center"
+    ));
+    let snapshot = server.snapshot();
+    assert_eq!(
+        snapshot.scene.characters,
+        vec![
+            server::StagedCharacter {
+                character_id: "Elira".to_owned(),
+                position: Some("left".to_owned()),
+            },
+            server::StagedCharacter {
+                character_id: "Kael".to_owned(),
+                position: Some("center".to_owned()),
+            },
+        ]
+    );
+
+    server.next();
+    assert!(server.scene().get("Elira").is_none());
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::SceneChanged(server::SceneChange::Shown { character_id, .. })) if character_id == "Elira"
+    ));
+
+    server.restore(serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap());
+    assert_eq!(server.scene(), &snapshot.scene);
+
+    let timeline = parser.parse("move Kael to right").unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.set_characters(characters);
+    server.start("start", 0);
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::SceneChanged(server::SceneChange::Shown { character_id, position: Some(position), .. }))
+            if character_id == "Kael" && position == "right"
+    ));
+    assert_eq!(
+        server.scene().get("Kael").unwrap().position.as_deref(),
+        Some("right")
+    );
+}
+
+#[test]
+fn test_bundle() {
+    let characters = CharacterRegistry::from_dir("test_files/characters", "test_files")
+        .unwrap()
+        .into_characters();
+    let timelines = parser::Parser::new(characters.to_owned())
+        .parse_dir("test_files")
+        .unwrap();
+    let bundle = bundle::Bundle::new(timelines, characters);
+
+    let loaded = bundle::Bundle::from_bytes(&bundle.to_bytes()).unwrap();
+    assert_eq!(loaded, bundle);
+    assert_eq!(
+        bundle::Bundle::from_bytes(br#"{"version": 0, "timelines": {}}"#),
+        Err(bundle::BundleError::Version { found: 0 })
+    );
+    assert!(matches!(
+        bundle::Bundle::from_bytes(b"{}"),
+        Err(bundle::BundleError::Format { .. })
+    ));
+
+    let mut server = loaded.into_server(evalexpr::HashMapContext::new());
+    assert!(server.character("Elira").is_some());
+    server.start("start", 0);
+    assert!(matches!(
+        server.next(),
+        Some(server::Event::Dialogue { text, .. }) if text == "Hello World!"
+    ));
+}
+
+#[test]
+fn test_bytecode() {
+    let timeline = parser::Parser::new(vec![])
+        .parse(
+            r#""Where to?"
+-- "Left"
+	"Left."
+-- "Right" if gold > 5
+if gold > 5:
+	"Rich."
+call "aside""#,
+        )
+        .unwrap();
+    let program = bytecode::Program::compile(Timelines::from([("start".to_owned(), timeline)]));
+    let code = program.code(program.id("start").unwrap()).unwrap();
+
+    assert!(matches!(&code[0], bytecode::Op::Line { choices, .. } if *choices == vec![1, 5]));
+    assert_eq!(code[4], bytecode::Op::Jump { target: 7 });
+    assert!(matches!(
+        &code[8],
+        bytecode::Op::JumpIfFalse { condition, target: 11 } if condition.source == "gold > 5"
+    ));
+    assert!(
+        matches!(code[12], bytecode::Op::Call { timeline, jump: false, .. } if program.name(timeline) == "aside")
+    );
+    // Called but not defined.
+    assert_eq!(program.id("aside"), None);
+}
+
+#[test]
+fn test_server() {
+    let parser = parser::Parser::new(vec![]);
+    let timelines = Timelines::from([
+        (
+            "start".to_owned(),
+            parser
+                .parse(
+                    r#""Where to?"
+-- "Left"
+	"Left."
+-- "Right"
+	"Right."
+call "middle"
+"Back at the start.""#,
+                )
+                .unwrap(),
+        ),
+        (
+            "middle".to_owned(),
+            parser
+                .parse(
+                    r#"if gold > 5:
+	"Rich."
+jump "end""#,
+                )
+                .unwrap(),
+        ),
+        ("end".to_owned(), parser.parse(r#""The end.""#).unwrap()),
+    ]);
+    let mut context = evalexpr::HashMapContext::new();
+    evalexpr::ContextWithMutableVariables::set_value(
+        &mut context,
+        "gold".to_owned(),
+        evalexpr::Value::Int(1),
+    )
+    .unwrap();
+    let mut server = server::Server::new(timelines, context);
+    server.start("start", 0);
+
+    let mut lines = Vec::new();
+    while let Some(event) = server.next() {
+        if let server::Event::Dialogue { text, choices, .. } = event {
+            if !choices.is_empty() {
+                server.choose(1);
+            }
+            lines.push(text);
+        }
+    }
+    // The jump replaces "middle", so "start" resumes after "end".
+    assert_eq!(
+        lines,
+        vec!["Where to?", "Right.", "The end.", "Back at the start."]
+    );
+}
+
+#[test]
+fn test_include() {
+    let mut parser = parser::Parser::new(vec![]);
+    assert!(parser.parse(r#"include "_common.shop""#).is_err());
+    parser.set_script_dir("test_includes");
+
+    assert_eq!(
+        parser.parse_file("test_includes/chapter.nobela").unwrap(),
+        parser
+            .parse(
+                r#""Welcome."
+-- "Shop"
+	"What will it be?"
+	-- "Potion"
+	-- "Nothing"
+-- "Leave"
+	"Bye.""#
+            )
+            .unwrap()
+    );
+
+    let error = parser
+        .parse_file("test_includes/missing.nobela")
+        .unwrap_err();
+    assert_eq!(error.path(), Some("test_includes/missing.nobela"));
+    assert_eq!(
+        error.line_col,
+        pest::error::LineColLocation::Span((2, 9), (2, 23))
+    );
+    assert!(matches!(
+        error.variant,
+        pest::error::ErrorVariant::CustomError { ref message }
+            if message == "Included file 'test_includes/_common/nope.nobela' not found."
+    ));
+
+    let error = parser
+        .parse_file("test_includes/cycle/a.nobela")
+        .unwrap_err();
+    assert_eq!(error.path(), Some("test_includes/cycle/b.nobela"));
+    assert_eq!(
+        error.line_col,
+        pest::error::LineColLocation::Span((2, 9), (2, 18))
+    );
+    assert!(matches!(
+        error.variant,
+        pest::error::ErrorVariant::CustomError { ref message }
+            if message == "Include cycle: test_includes/cycle/a.nobela -> test_includes/cycle/b.nobela -> test_includes/cycle/a.nobela.\nIncluded from test_includes/cycle/a.nobela:1."
+    ));
+
+    // Fragments to include aren't timelines of their own.
+    let timelines = parser.parse_dir("test_fragments").unwrap();
+    assert_eq!(timelines.keys().collect::<Vec<_>>(), vec!["intro"]);
+    assert_eq!(
+        timelines["intro"],
+        parser.parse("\"Welcome.\"\n\"Hello again.\"").unwrap()
+    );
+
+    fn assert_sync<T: Sync>() {}
+    assert_sync::<parser::Parser>();
+
+    // Each parse keeps its own includes and loops, so threads can share a parser.
+    std::thread::scope(|scope| {
+        let parser = &parser;
+        let includes = scope.spawn(move || {
+            (0..200).all(|_| parser.parse_file("test_includes/chapter.nobela").is_ok())
+        });
+        let loops = scope.spawn(move || {
+            (0..200)
+                .all(|_| parser.parse("loop:\n\tbreak").is_ok() && parser.parse("break").is_err())
+        });
+        assert!(includes.join().unwrap() && loops.join().unwrap());
+    });
+}
+
+/// Runs a server the way a game does, applying [`server::Event::Set`] to its own variables.
+struct Host<'a> {
+    server: &'a mut server::Server,
+    context: evalexpr::HashMapContext,
+}
+
+impl<'a> Iterator for Host<'a> {
+    type Item = server::Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = self.server.next()?;
+        if let server::Event::Set {
+            variable_name,
+            new_value,
+        } = &event
+        {
+            evalexpr::ContextWithMutableVariables::set_value(
+                &mut self.context,
+                variable_name.to_owned(),
+                new_value.to_owned(),
+            )
+            .unwrap();
+            self.server.set_context(self.context.to_owned());
+        }
+        Some(event)
+    }
+}
+
+#[test]
+fn test_call_arguments() {
+    let parser = parser::Parser::new(vec![]);
+    let timelines = Timelines::from([
+        (
+            "start".to_owned(),
+            parser
+                .parse(
+                    r#"mood = 0
+call "greet"(who="Elira", mood=mood + 2)
+"{who} is gone, mood {mood}.""#,
+                )
+                .unwrap(),
+        ),
+        (
+            "greet".to_owned(),
+            parser
+                .parse(
+                    r#""Hi {who}, mood {mood}."
+mood = mood * 10
+"Mood {mood}.""#,
+                )
+                .unwrap(),
+        ),
+    ]);
+    let mut context = evalexpr::HashMapContext::new();
+    evalexpr::ContextWithMutableVariables::set_value(
+        &mut context,
+        "who".to_owned(),
+        evalexpr::Value::String("nobody".to_owned()),
+    )
+    .unwrap();
+    let mut server = server::Server::new(timelines, context.to_owned());
+    server.start("start", 0);
+
+    let mut host = Host {
+        server: &mut server,
+        context,
+    };
+    let mut lines = Vec::new();
+    let mut snapshot = None;
+    while let Some(event) = host.next() {
+        if let server::Event::Dialogue { text, .. } = event {
+            if snapshot.is_none() {
+                snapshot = Some(host.server.snapshot());
+            }
+            lines.push(text);
+        }
+    }
+    assert_eq!(
+        lines,
+        vec!["Hi Elira, mood 2.", "Mood 20.", "nobody is gone, mood 0."]
+    );
+    // Only the locals were kept by the server, the host got the rest.
+    assert_eq!(
+        evalexpr::Context::get_value(&host.context, "mood"),
+        Some(&evalexpr::Value::Int(0))
+    );
+    assert_eq!(host.server.snapshot().variables, HashMap::new());
+
+    let snapshot = snapshot.unwrap();
+    assert_eq!(
+        snapshot.locals_stack[1].get("who"),
+        Some(&evalexpr::Value::String("Elira".to_owned()))
+    );
+    server.restore(snapshot);
+    assert!(matches!(
+        server.find(|e| matches!(e, server::Event::Dialogue { .. })),
+        Some(server::Event::Dialogue { text, .. }) if text == "Mood 20."
+    ));
+}
+
+#[test]
+fn test_return_end() {
+    let parser = parser::Parser::new(vec![]);
+    let timelines = Timelines::from([
+        (
+            "start".to_owned(),
+            parser
+                .parse(
+                    r#"call "shop"
+"Back."
+if true:
+	end
+"Never shown.""#,
+                )
+                .unwrap(),
+        ),
+        (
+            "shop".to_owned(),
+            parser
+                .parse(
+                    r#""Closed."
+if ending == 0:
+	return
+"Never shown either.""#,
+                )
+                .unwrap(),
+        ),
+    ]);
+    let mut context = evalexpr::HashMapContext::new();
+    evalexpr::ContextWithMutableVariables::set_value(
+        &mut context,
+        "ending".to_owned(),
+        evalexpr::Value::Int(0),
+    )
+    .unwrap();
+    let mut server = server::Server::new(timelines, context);
+    server.start("start", 0);
+
+    let events: Vec<String> = server
+        .by_ref()
+        .filter_map(|event| match event {
+            server::Event::Dialogue { text, .. } => Some(text),
+            server::Event::End => Some("END".to_owned()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(events, vec!["Closed.", "Back.", "END"]);
+    assert!(server.next().is_none());
+    assert!(server.snapshot().timeline_stack.is_empty());
+}
+
+#[test]
+fn test_loops() {
+    let parser = parser::Parser::new(vec![]);
+    assert!(parser.parse("break").is_err());
+    assert!(parser.parse("loop:\n\tbreak\nbreaking = 1").is_ok());
+
+    let timeline = parser
+        .parse(
+            r#"visits = 0
+loop:
+	"Ask about?"
+	-- "The weather"
+		"Sunny."
+	-- "Leave"
+		break
+"Bye."
+while visits < 2:
+	visits = visits + 1
+	"Visit {visits}.""#,
+        )
+        .unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.start("start", 0);
+
+    let mut host = Host {
+        server: &mut server,
+        context: evalexpr::HashMapContext::new(),
+    };
+    let mut answers = vec![1, 0];
+    let mut lines = Vec::new();
+    while let Some(event) = host.next() {
+        if let server::Event::Dialogue { text, choices, .. } = event {
+            if !choices.is_empty() {
+                host.server.choose(answers.pop().unwrap());
+            }
+            lines.push(text);
+        }
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "Ask about?",
+            "Sunny.",
+            "Ask about?",
+            "Bye.",
+            "Visit 1.",
+            "Visit 2."
+        ]
+    );
+}
+
+#[test]
+fn test_once_choices() {
+    let parser = parser::Parser::new(vec![]);
+    assert!(parser.parse("\"Hi.\"\n-- else\n-- else").is_err());
+
+    let timeline = parser
+        .parse(
+            r#"loop:
+	"Ask about?"
+	--* "The war"
+		"It was long."
+	--* "The king" #line:king01
+		"He is dead."
+	-- else
+		"Nothing left to ask."
+		break
+"Bye.""#,
+        )
+        .unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.start("start", 0);
+
+    let mut lines = Vec::new();
+    while let Some(event) = server.next() {
+        if let server::Event::Dialogue { text, choices, .. } = event {
+            if let Some(choice) = choices.iter().position(|c| !c.hidden) {
+                server.choose(choice);
+            }
+            lines.push(text);
+        }
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "Ask about?",
+            "It was long.",
+            "Ask about?",
+            "He is dead.",
+            "Ask about?",
+            "Nothing left to ask.",
+            "Bye."
+        ]
+    );
+    assert!(server.was_chosen("start:2"));
+    assert!(server.was_chosen("king01"));
+
+    let snapshot: server::Snapshot =
+        serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap();
+    assert_eq!(snapshot.chosen_choices.len(), 3);
+
+    // Without a fallback, a menu with nothing left to choose is skipped.
+    let timelines = Timelines::from([
+        (
+            "start".to_owned(),
+            parser
+                .parse("call \"ask\"\ncall \"ask\"\ncall \"ask\"\n\"Bye.\"")
+                .unwrap(),
+        ),
+        (
+            "ask".to_owned(),
+            parser
+                .parse("\"Ask?\"\n--* \"The war\"\n\t\"Long.\"\n--* \"The king\"\n\t\"Dead.\"")
+                .unwrap(),
+        ),
+    ]);
+    let mut server = server::Server::new(timelines, evalexpr::HashMapContext::new());
+    server.start("start", 0);
+
+    let mut lines = Vec::new();
+    while let Some(event) = server.next() {
+        if let server::Event::Dialogue { text, choices, .. } = event {
+            if let Some(choice) = choices.iter().position(|c| !c.hidden) {
+                server.choose(choice);
+            }
+            lines.push(format!("{text} {}", choices.len()));
+        }
+    }
+    assert_eq!(
+        lines,
+        vec!["Ask? 2", "Long. 0", "Ask? 2", "Dead. 0", "Ask? 0", "Bye. 0"]
+    );
+}
+
+#[test]
+fn test_visits() {
+    let parser = parser::Parser::new(vec![]);
+    let start = parser
+        .parse(
+            r#"call "bar"
+call "tavern"
+call "tavern"
+if seen("tavern"):
+	if seen("market") == false:
+		"Back again."
+visited = visits("tavern")
+drinks = visits("bar") + visits("tavern#bar")
+"{visited} visits, {drinks} drinks."
+call "tavern""#,
+        )
+        .unwrap();
+    let tavern = parser
+        .parse(
+            r#"if visits("tavern") == 1:
+	first_drinks = visits("bar")
+if visits("tavern") > 1:
+	label bar
+	"Another one."
+if visits("bar") > 1:
+	"Too many.""#,
+        )
+        .unwrap();
+    let bar = parser.parse(r#""At the bar.""#).unwrap();
+    let timelines = Timelines::from([
+        ("start".to_owned(), start),
+        ("tavern".to_owned(), tavern),
+        ("bar".to_owned(), bar),
+    ]);
+    let mut server = server::Server::new(timelines.to_owned(), evalexpr::HashMapContext::new());
+    server.start("start", 0);
+
+    let mut host = Host {
+        server: &mut server,
+        context: evalexpr::HashMapContext::new(),
+    };
+    let mut lines = Vec::new();
+    for event in host.by_ref() {
+        if let server::Event::Dialogue { text, .. } = event {
+            lines.push(text);
+        }
+    }
+    assert_eq!(
+        lines,
+        vec![
+            "At the bar.",
+            "Another one.",
+            "Back again.",
+            "2 visits, 2 drinks.",
+            "Another one.",
+            "Too many."
+        ]
+    );
+    // The label of the tavern, not the timeline of the same name, even before it was reached.
+    assert_eq!(
+        evalexpr::Context::get_value(&host.context, "first_drinks"),
+        Some(&evalexpr::Value::Int(0))
+    );
+    assert_eq!(server.visits("start"), 1);
+    assert_eq!(server.visits("bar"), 1);
+    assert_eq!(server.visits("tavern#bar"), 2);
+
+    let snapshot: server::Snapshot =
+        serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap();
+    assert_eq!(snapshot.visits.get("tavern"), Some(&3));
+    let mut restored = server::Server::new(timelines, evalexpr::HashMapContext::new());
+    restored.restore(snapshot);
+    assert_eq!(restored.visits("tavern#bar"), 2);
+}
+
+#[test]
+fn test_alternatives() {
+    fn lines(events: impl Iterator<Item = server::Event>) -> Vec<String> {
+        events
+            .filter_map(|event| match event {
+                server::Event::Dialogue { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    let parser = parser::Parser::new(vec![]);
+    let timeline = parser
+        .parse(
+            r#"n = 0
+while n < 4:
+	"{&Morning|Noon|Night}{!, stranger|, again}."
+	sequence:
+		"One."
+		"Two."
+	once:
+		"Only once."
+	n = n + 1"#,
+        )
+        .unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.start("start", 0);
+    assert_eq!(
+        lines(Host {
+            server: &mut server,
+            context: evalexpr::HashMapContext::new(),
+        }),
+        vec![
+            "Morning, stranger.",
+            "One.",
+            "Only once.",
+            "Noon, again.",
+            "Two.",
+            "Night.",
+            "Two.",
+            "Morning.",
+            "Two."
+        ]
+    );
+
+    let timeline = parser
+        .parse(
+            r#"loop:
+	shuffle:
+		"A."
+		"B."
+		"C."
+	"{~x|y|z}""#,
+        )
+        .unwrap();
+    let timelines = Timelines::from([("start".to_owned(), timeline)]);
+    let mut server = server::Server::new(timelines.to_owned(), evalexpr::HashMapContext::new());
+    server.set_seed(42);
+    server.start("start", 0);
+    let first = lines(server.by_ref().take(40));
+    let snapshot: server::Snapshot =
+        serde_json::from_str(&serde_json::to_string(&server.snapshot()).unwrap()).unwrap();
+    let rest = lines(server.by_ref().take(40));
+    assert!(first.iter().any(|text| text == "A.") && first.iter().any(|text| text == "B."));
+
+    let mut replay = server::Server::new(timelines.to_owned(), evalexpr::HashMapContext::new());
+    replay.set_seed(42);
+    replay.start("start", 0);
+    let replayed = lines(replay.by_ref().take(40));
+    assert_eq!(first, replayed);
+
+    let mut restored = server::Server::new(timelines, evalexpr::HashMapContext::new());
+    restored.restore(snapshot);
+    let restored = lines(restored.by_ref().take(40));
+    assert_eq!(rest, restored);
+
+    // Sites are keyed by line ID when there is one, so saves survive edits above them. Hidden
+    // choices don't use up their alternatives.
+    let timeline = parser
+        .parse(
+            r#"loop:
+	"{&Morning|Noon}" #line:greet
+	-- "{&Go|Leave}" if false #line:go
+	-- "{&Stay|Wait}" #line:stay
+	once:
+		"First." #line:first"#,
+        )
+        .unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.start("start", 0);
+    for _ in 0..2 {
+        while !matches!(server.next(), Some(server::Event::Dialogue { choices, .. }) if !choices.is_empty())
+        {
+        }
+        server.choose(1);
+    }
+    let alternative_visits = server.snapshot().alternative_visits;
+    assert_eq!(alternative_visits.get("greet:0"), Some(&2));
+    assert_eq!(alternative_visits.get("stay:0"), Some(&2));
+    assert_eq!(alternative_visits.get("go:0"), None);
+    assert_eq!(alternative_visits.get("first"), Some(&1));
+
+    // Templates that are expressions stay expressions.
+    let context = evalexpr::context_map! { "met_before" => false }.unwrap();
+    let server = server::Server::new(Timelines::new(), context);
+    assert_eq!(
+        server.render_text("{!met_before} {!met_before:upper}"),
+        Ok("true TRUE".to_owned())
+    );
+    assert_eq!(
+        server.render_text("{!Hi there|Hi}"),
+        Ok("Hi there".to_owned())
+    );
+    // With a `|`, it's alternatives even if it could be read as an expression.
+    assert_eq!(server.render_text("{!Hi||Bye}"), Ok("Hi".to_owned()));
+    assert_eq!(server.render_text("{!Hi||Bye}"), Ok("".to_owned()));
+    assert_eq!(server.render_text("{!Hi||Bye}"), Ok("Bye".to_owned()));
+    assert_eq!(
+        server.render_text("{ !met_before || false }"),
+        Ok("true".to_owned())
+    );
+}
+
+#[test]
+fn test_random() {
+    let parser = parser::Parser::new(vec![]);
+    let timeline = parser
+        .parse(
+            r#"loop:
+	roll = random(1, 6)
+	lucky = chance(0.5)
+	"{roll} {lucky}""#,
+        )
+        .unwrap();
+    let timelines = Timelines::from([("start".to_owned(), timeline)]);
+    let run = |server: &mut server::Server, count: usize| -> Vec<String> {
+        Host {
+            server,
+            context: evalexpr::HashMapContext::new(),
+        }
+        .filter_map(|event| match event {
+            server::Event::Dialogue { text, .. } => Some(text),
+            _ => None,
+        })
+        .take(count)
+        .collect()
+    };
+
+    let mut server = server::Server::new(timelines.to_owned(), evalexpr::HashMapContext::new());
+    server.set_seed(7);
+    server.start("start", 0);
+    let first = run(&mut server, 50);
+    let snapshot = server.snapshot();
+    let rest = run(&mut server, 50);
+    for line in &first {
+        let (roll, lucky) = line.split_once(' ').unwrap();
+        assert!((1..=6).contains(&roll.parse::<i64>().unwrap()));
+        assert!(lucky == "true" || lucky == "false");
+    }
+    assert!(first.iter().any(|line| line.starts_with('1')));
+    assert!(first.iter().any(|line| line.starts_with('6')));
+    assert!(first.iter().any(|line| line.ends_with("true")));
+    assert!(first.iter().any(|line| line.ends_with("false")));
+
+    let mut replay = server::Server::new(timelines.to_owned(), evalexpr::HashMapContext::new());
+    replay.set_seed(7);
+    replay.start("start", 0);
+    assert_eq!(run(&mut replay, 50), first);
+
+    let mut restored = server::Server::new(timelines, evalexpr::HashMapContext::new());
+    restored.restore(snapshot);
+    assert_eq!(run(&mut restored, 50), rest);
+
+    assert_eq!(server.render_text("{random(3, 3)}"), Ok("3".to_owned()));
+    assert_eq!(
+        server.render_text("{chance(0)} {chance(1)}"),
+        Ok("false true".to_owned())
+    );
+    server.set_strict_templates(true);
+    assert!(server.render_text("{random(6, 1)}").is_err());
+}
+
+#[test]
+fn test_match() {
+    let parser = parser::Parser::new(vec![]);
+    let mut state = parser::ParseState::default();
+    let timeline = parser
+        .parse_with(
+            r#"match mood:
+	case 1:
+		"Happy."
+	case 2:
+		match weather:
+			case "rain":
+				"Sad and wet."
+			default:
+				"Sad."
+	case 1:
+		"Never."
+	default:
+		"Meh."
+"How are you?"
+-- "Fine"
+	match mood + 1:
+		case 4:
+			"Good."
+-- "Bye"
+"Done.""#,
+            &mut state,
+        )
+        .unwrap();
+    let warnings = &state.warnings;
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].to_string().contains("Duplicate case '1'"));
+    assert_eq!(
+        warnings[0].line_col,
+        pest::error::LineColLocation::Span((10, 7), (10, 8))
+    );
+
+    let lines = |mood: i64, weather: &str| {
+        let mut context = evalexpr::HashMapContext::new();
+        evalexpr::ContextWithMutableVariables::set_value(
+            &mut context,
+            "mood".to_owned(),
+            mood.into(),
+        )
+        .unwrap();
+        evalexpr::ContextWithMutableVariables::set_value(
+            &mut context,
+            "weather".to_owned(),
+            weather.into(),
+        )
+        .unwrap();
+        let mut server = server::Server::new(
+            Timelines::from([("start".to_owned(), timeline.to_owned())]),
+            context,
+        );
+        server.start("start", 0);
+
+        let mut lines = Vec::new();
+        while let Some(event) = server.next() {
+            if let server::Event::Dialogue { text, choices, .. } = event {
+                if !choices.is_empty() {
+                    server.choose(0);
+                }
+                lines.push(text);
+            }
+        }
+        lines
+    };
+    assert_eq!(lines(1, "sun"), vec!["Happy.", "How are you?", "Done."]);
+    assert_eq!(
+        lines(2, "rain"),
+        vec!["Sad and wet.", "How are you?", "Done."]
+    );
+    assert_eq!(lines(2, "sun"), vec!["Sad.", "How are you?", "Done."]);
+    assert_eq!(
+        lines(3, "sun"),
+        vec!["Meh.", "How are you?", "Good.", "Done."]
+    );
+
+    assert!(parser
+        .parse("match mood:\n\tdefault:\n\t\t\"A\"\n\tdefault:\n\t\t\"B\"")
+        .is_err());
+    assert!(parser
+        .parse("match mood:\n\tcase other:\n\t\t\"A\"")
+        .is_err());
+
+    // Cases are compared by value.
+    let mut state = parser::ParseState::default();
+    parser
+        .parse_with("match mood:\n\tcase \"a\":\n\t\t\"A\"\n\tcase  \"a\":\n\t\t\"B\"\n\tcase 1.0:\n\t\t\"C\"\n\tcase 1:\n\t\t\"D\"", &mut state)
+        .unwrap();
+    assert_eq!(state.warnings.len(), 1);
+
+    // `match` is not a keyword.
+    let timeline = parser.parse("match = 1\nmatchless = 2").unwrap();
+    assert!(
+        matches!(&timeline[0], parser::Stmt::Set { variable_name, .. } if variable_name == "match")
+    );
+}
+
+#[test]
+fn test_multiline_text() {
+    let script = r#""""
+	The road is [i]long[/i],
+	and dark.
+
+	Or is it?
+	"""
+-- """Take
+	the road""" #line:road01
+	"""Fine."""
+-- "Stay""#;
+
+    let mut parser = parser::Parser::new(vec![]);
+    let timeline = parser.parse(script).unwrap();
+    assert_eq!(
+        timeline[0],
+        parser::Stmt::Dialogue {
+            character_id: None,
+            speaker: None,
+            text: "The road is [i]long[/i], and dark.\nOr is it?".to_owned(),
+            expression: None,
+            line_id: None,
+            voice: None,
+        }
+    );
+    assert_eq!(
+        timeline[1],
+        parser::Stmt::Choice {
+            text: "Take the road".to_owned(),
+            condition: None,
+            line_id: Some("road01".to_owned()),
+            once: false,
+            fallback: false,
+        }
+    );
+
+    parser.set_line_join(parser::LineJoin::Keep);
+    let timeline = parser.parse(script).unwrap();
+    let mut server = server::Server::new(
+        Timelines::from([("start".to_owned(), timeline)]),
+        evalexpr::HashMapContext::new(),
+    );
+    server.start("start", 0);
+    match server.next() {
+        Some(server::Event::Dialogue { text, choices, .. }) => {
+            assert_eq!(text, "The road is long,\nand dark.\n\nOr is it?");
+            assert_eq!(choices[0].text, "Take\nthe road");
+        }
+        _ => panic!("Expected a dialogue."),
+    }
+
+    let error = parser
+        .parse("\"Intro.\"\n\"\"\"\n\tThe road is long,\n\tand [b]dark.\"\"\"")
+        .unwrap_err();
+    assert_eq!(error.line_col, pest::error::LineColLocation::Pos((4, 6)));
+}
 
-//TODO Create tests for server.
\ No newline at end of file
+//TODO Create tests for server.
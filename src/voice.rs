@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use crate::{parser::Stmt, server::Timelines};
+
+pub const DEFAULT_VOICE_PATTERN: &str = "voice/{character_id}/{line_id}.ogg";
+
+/// A voiced line whose audio file couldn't be found.
+#[derive(Debug, PartialEq)]
+pub struct MissingVoice {
+    pub timeline_name: String,
+    pub index: usize,
+    pub line_id: Option<String>,
+    /// `None` if no path could be resolved for the line, e.g. because it has no line ID.
+    pub voice_path: Option<String>,
+}
+
+/// Resolves the voice file of a dialogue line.
+///
+/// An explicit `voice "file"` clause always wins. Otherwise `{character_id}` and `{line_id}`
+/// in `pattern` are replaced, which requires both to be known.
+pub fn resolve_voice_path(
+    pattern: Option<&str>,
+    voice: Option<&str>,
+    character_id: Option<&str>,
+    line_id: Option<&str>,
+) -> Option<String> {
+    if let Some(voice) = voice {
+        return Some(voice.to_owned());
+    }
+
+    match (pattern, character_id, line_id) {
+        (Some(pattern), Some(character_id), Some(line_id)) => Some(
+            pattern
+                .replace("{character_id}", character_id)
+                .replace("{line_id}", line_id),
+        ),
+        _ => None,
+    }
+}
+
+/// Lists the character lines in `timelines` without an audio file under `asset_dir`.
+pub fn missing_voice_files(
+    timelines: &Timelines,
+    pattern: &str,
+    asset_dir: &str,
+) -> Vec<MissingVoice> {
+    let mut missing = Vec::new();
+
+    for (timeline_name, timeline) in timelines {
+        for (index, stmt) in timeline.iter().enumerate() {
+            if let Stmt::Dialogue {
+                character_id,
+                line_id,
+                voice,
+                ..
+            } = stmt
+            {
+                if character_id.is_none() && voice.is_none() {
+                    continue;
+                }
+
+                let voice_path = resolve_voice_path(
+                    Some(pattern),
+                    voice.as_deref(),
+                    character_id.as_deref(),
+                    line_id.as_deref(),
+                );
+                let exists = match &voice_path {
+                    Some(voice_path) => Path::new(asset_dir).join(voice_path).is_file(),
+                    None => false,
+                };
+
+                if !exists {
+                    missing.push(MissingVoice {
+                        timeline_name: timeline_name.to_owned(),
+                        index,
+                        line_id: line_id.to_owned(),
+                        voice_path,
+                    });
+                }
+            }
+        }
+    }
+
+    missing.sort_by(|a, b| (&a.timeline_name, a.index).cmp(&(&b.timeline_name, b.index)));
+    missing
+}